@@ -86,13 +86,15 @@ fn build_impl_block(
         .zip(idents)
         .map(|(var, id)| {
             let v_id = &var.ident;
+            // Variants only available on some platforms need the same `cfg` on their arm
+            let cfgs = var.attrs.iter().filter(|attr| attr.path().is_ident("cfg"));
             if let Some((_, ref expr)) = var.discriminant {
                 // If we have an original expression for the variant, then use it..
-                parse_quote!(#expr => #enum_name::#v_id,)
+                parse_quote!(#(#cfgs)* #expr => #enum_name::#v_id,)
             } else {
                 // otherwise, fallback to pam_sys
                 // FIXME: This guard should not be necessary
-                parse_quote!(x if x == pam_sys::#id => #enum_name::#v_id,)
+                parse_quote!(#(#cfgs)* x if x == pam_sys::#id => #enum_name::#v_id,)
             }
         })
         .collect();
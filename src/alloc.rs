@@ -0,0 +1,160 @@
+//! Allocation of memory shared with PAM
+//!
+//! All memory this crate hands to PAM (e.g. conversation responses) or frees on its behalf
+//! (e.g. environment lists) goes through the functions in this module. By default they use
+//! `calloc` and `free` from libc, like PAM itself.
+use libc::{c_char, c_void, size_t};
+use std::ffi::CStr;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// The pair of functions used to allocate and free memory shared with PAM
+#[derive(Debug, Clone, Copy)]
+pub struct CAllocator {
+    /// Allocate zeroed memory for the given number of elements of the given size, like
+    /// `calloc`, returning null on failure
+    pub calloc: unsafe extern "C" fn(size_t, size_t) -> *mut c_void,
+    /// Free memory returned by `calloc` or by PAM, like `free`
+    pub free: unsafe extern "C" fn(*mut c_void),
+}
+
+impl CAllocator {
+    /// The default allocator, using libc
+    pub const LIBC: CAllocator = CAllocator {
+        calloc: libc::calloc,
+        free: libc::free,
+    };
+}
+
+static ALLOCATOR: AtomicPtr<CAllocator> = AtomicPtr::new(ptr::null_mut());
+
+fn allocator() -> &'static CAllocator {
+    let current = ALLOCATOR.load(Ordering::Acquire);
+    if current.is_null() {
+        &CAllocator::LIBC
+    } else {
+        unsafe { &*current }
+    }
+}
+
+/// Replace the allocator used for memory shared with PAM
+///
+/// # Safety
+///
+/// PAM frees the memory allocated here (and the other way round), so `allocator` has to
+/// be compatible with the allocator PAM uses. Memory is freed with whichever allocator is
+/// set at that time, so this should be called once, before any PAM function is used.
+pub unsafe fn set_allocator(allocator: &'static CAllocator) {
+    ALLOCATOR.store(
+        allocator as *const CAllocator as *mut CAllocator,
+        Ordering::Release,
+    );
+}
+
+/// Allocate zeroed memory for `count` elements of `size` bytes, null on failure
+pub fn alloc_c(count: usize, size: usize) -> *mut c_void {
+    unsafe { (allocator().calloc)(count as size_t, size as size_t) }
+}
+
+/// Free memory allocated by `alloc_c`, `dup_c` or by PAM, null is ignored
+///
+/// # Safety
+///
+/// `ptr` has to be null or point to memory from the current allocator, which is not used
+/// afterwards.
+pub unsafe fn free_c(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        (allocator().free)(ptr);
+    }
+}
+
+/// Copy `s` into newly allocated memory, like `strdup`, null on failure
+pub fn dup_c(s: &CStr) -> *mut c_char {
+    dup_bytes(s.to_bytes_with_nul())
+}
+
+/// Copy `data` into newly allocated memory, null on failure
+///
+/// At least one byte is allocated, so an empty `data` still yields a valid pointer.
+pub fn dup_bytes(data: &[u8]) -> *mut c_char {
+    let ptr = alloc_c(data.len().max(1), 1);
+    if !ptr.is_null() {
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len()) };
+    }
+    ptr as *mut c_char
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::ffi::CString;
+
+    thread_local! {
+        static LIVE: Cell<isize> = const { Cell::new(0) };
+        // The number of allocations which still succeed, unlimited if `None`
+        static REMAINING: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    unsafe extern "C" fn counting_calloc(count: size_t, size: size_t) -> *mut c_void {
+        match REMAINING.with(Cell::get) {
+            Some(0) => return ptr::null_mut(),
+            remaining => REMAINING.with(|r| r.set(remaining.map(|n| n - 1))),
+        }
+        LIVE.with(|live| live.set(live.get() + 1));
+        libc::calloc(count, size)
+    }
+
+    unsafe extern "C" fn counting_free(ptr: *mut c_void) {
+        LIVE.with(|live| live.set(live.get() - 1));
+        libc::free(ptr)
+    }
+
+    static COUNTING: CAllocator = CAllocator {
+        calloc: counting_calloc,
+        free: counting_free,
+    };
+
+    // The number of live allocations of the current thread, counted from the first call
+    //
+    // This installs a counting allocator, which forwards to libc, so it does not affect
+    // other tests running at the same time.
+    pub(crate) fn live_allocations() -> isize {
+        unsafe { set_allocator(&COUNTING) };
+        LIVE.with(|live| live.get())
+    }
+
+    // Let only the next `count` allocations of the current thread succeed, or all of them
+    // with `None`
+    pub(crate) fn fail_allocations_after(count: Option<usize>) {
+        unsafe { set_allocator(&COUNTING) };
+        REMAINING.with(|remaining| remaining.set(count));
+    }
+
+    #[test]
+    fn dup_and_free() {
+        let before = live_allocations();
+        let text = CString::new("secret").unwrap();
+        let dup = dup_c(&text);
+        assert_eq!(unsafe { CStr::from_ptr(dup) }, text.as_c_str());
+        let empty = dup_bytes(&[]);
+        assert!(!empty.is_null());
+        assert_eq!(live_allocations(), before + 2);
+
+        unsafe {
+            free_c(dup as *mut c_void);
+            free_c(empty as *mut c_void);
+            free_c(ptr::null_mut());
+        }
+        assert_eq!(live_allocations(), before);
+
+        fail_allocations_after(Some(1));
+        let dup = dup_c(&text);
+        assert!(!dup.is_null());
+        assert!(dup_c(&text).is_null());
+        fail_allocations_after(None);
+        unsafe { free_c(dup as *mut c_void) };
+        assert_eq!(live_allocations(), before);
+    }
+}
@@ -0,0 +1,149 @@
+//! Logging the results of authentications to syslog
+use crate::{
+    get_item_cstr, get_service, PamError, PamHandle, PamItemType, PamResult, PamReturnCode,
+};
+
+use libc::c_int;
+use std::ffi::{CStr, CString};
+
+/// Where `Client` logs the results of `authenticate` to, see `Client::set_audit_log`
+///
+/// Each entry contains the service, the user and the remote host (as far as they are set)
+/// and the name of the return code, e.g.
+/// `pam_authenticate: service=login user=alice rhost=example.org result=Auth_Err`.
+/// Authentication tokens are never logged.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    facility: c_int,
+    ident: Option<CString>,
+}
+
+impl AuditLog {
+    /// Log to the `LOG_AUTHPRIV` facility, with the ident of the process (usually the name
+    /// of the program)
+    pub fn new() -> AuditLog {
+        AuditLog {
+            facility: libc::LOG_AUTHPRIV,
+            ident: None,
+        }
+    }
+
+    /// Log to `facility` (e.g. `libc::LOG_AUTH`) instead
+    pub fn facility(mut self, facility: c_int) -> AuditLog {
+        self.facility = facility;
+        self
+    }
+
+    /// Prefix each entry with `ident`, e.g. `ident: pam_authenticate: ...`
+    ///
+    /// The entries are still logged with the ident of the process: `openlog` is never
+    /// called, as it would change the ident of all entries of the process, including the
+    /// ones the application logs itself. Fails with `PAM_BUF_ERR` if `ident` contains a
+    /// nul byte.
+    pub fn ident(mut self, ident: &str) -> PamResult<AuditLog> {
+        let ident = CString::new(ident).map_err(|_| PamError(PamReturnCode::Buf_Err))?;
+        self.ident = Some(ident);
+        Ok(self)
+    }
+
+    /// Log the result `code` of an authentication on `handle`
+    pub(crate) fn log_authentication(&self, handle: &PamHandle, code: PamReturnCode) {
+        let service = get_service(handle);
+        let item = |item_type| get_item_cstr(handle, item_type).ok().flatten();
+        let entry = format_entry(
+            service.as_deref(),
+            item(PamItemType::User),
+            item(PamItemType::RHost),
+            code,
+        );
+        let level = match code {
+            PamReturnCode::Success => libc::LOG_INFO,
+            _ => libc::LOG_NOTICE,
+        };
+        // Like `LOG_MAKEPRI`, which the libc crate does not provide
+        let priority = self.facility | level;
+        // Never pass the entry as format string
+        const FORMAT: [u8; 3] = [b'%', b's', 0];
+        const FORMAT_IDENT: [u8; 7] = [b'%', b's', b':', b' ', b'%', b's', 0];
+        unsafe {
+            match &self.ident {
+                Some(ident) => libc::syslog(
+                    priority,
+                    FORMAT_IDENT.as_ptr() as *const libc::c_char,
+                    ident.as_ptr(),
+                    entry.as_ptr(),
+                ),
+                None => libc::syslog(
+                    priority,
+                    FORMAT.as_ptr() as *const libc::c_char,
+                    entry.as_ptr(),
+                ),
+            }
+        }
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> AuditLog {
+        AuditLog::new()
+    }
+}
+
+// Unset values are logged as `?`. Anything but printable ASCII is replaced, so values
+// cannot forge fields or entries.
+fn format_entry(
+    service: Option<&str>,
+    user: Option<&CStr>,
+    rhost: Option<&CStr>,
+    code: PamReturnCode,
+) -> CString {
+    fn field(entry: &mut Vec<u8>, name: &str, value: Option<&[u8]>) {
+        entry.push(b' ');
+        entry.extend_from_slice(name.as_bytes());
+        entry.push(b'=');
+        match value {
+            None | Some(b"") => entry.push(b'?'),
+            Some(value) => entry.extend(value.iter().map(|&c| match c {
+                b'!'..=b'~' if c != b'=' => c,
+                _ => b'?',
+            })),
+        }
+    }
+
+    let mut entry = b"pam_authenticate:".to_vec();
+    field(&mut entry, "service", service.map(str::as_bytes));
+    field(&mut entry, "user", user.map(CStr::to_bytes));
+    field(&mut entry, "rhost", rhost.map(CStr::to_bytes));
+    field(&mut entry, "result", Some(format!("{:?}", code).as_bytes()));
+    CString::new(entry).expect("no nul bytes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_entries() {
+        let user = CString::new("alice").unwrap();
+        let rhost = CString::new("example.org").unwrap();
+        let entry = format_entry(
+            Some("login"),
+            Some(&user),
+            Some(&rhost),
+            PamReturnCode::Auth_Err,
+        );
+        assert_eq!(
+            entry.to_str(),
+            Ok("pam_authenticate: service=login user=alice rhost=example.org result=Auth_Err")
+        );
+
+        let user = CString::new("eve result=Success\nforged").unwrap();
+        let entry = format_entry(None, Some(&user), None, PamReturnCode::Success);
+        assert_eq!(
+            entry.to_str(),
+            Ok("pam_authenticate: service=? user=eve?result?Success?forged rhost=? result=Success")
+        );
+
+        assert!(AuditLog::new().ident("pam\0f").is_err());
+    }
+}
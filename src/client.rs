@@ -1,7 +1,17 @@
 //! Authentication related structure and functions
-use std::{env, ffi::CStr, os::raw::c_char};
+use std::{
+    any, env,
+    ffi::{CStr, CString},
+    fmt,
+    num::NonZeroUsize,
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::{conv, enums::*, functions::*, types::*};
+use crate::{conv, enums::*, ffi, functions::*, probe, types::*, ExpiryInfo};
+
+#[cfg(any(test, feature = "test-util"))]
+use std::path::Path;
 
 /// Main struct to authenticate a user
 ///
@@ -32,11 +42,255 @@ use crate::{conv, enums::*, functions::*, types::*};
 pub struct Client<'a, C: conv::Conversation> {
     /// Flag indicating whether the Client should close the session on drop
     pub close_on_drop: bool,
+    service: String,
     handle: &'a mut PamHandle,
-    conversation: Box<C>,
+    // Boxed, as PAM keeps a pointer to it which has to stay valid when the `Client` moves
+    conversation: Box<Tracked<C>>,
     is_authenticated: bool,
+    new_authtok_required: bool,
     has_open_session: bool,
+    credentials_deleted: bool,
     last_code: PamReturnCode,
+    silent: bool,
+    handed_off: bool,
+    session_env: Option<Vec<(String, String)>>,
+    #[cfg(feature = "syslog")]
+    audit_log: Option<crate::AuditLog>,
+}
+
+/// String items to set at once with `Client::set_items`
+///
+/// Only the fields which are `Some` are set.
+#[derive(Debug, Clone, Default)]
+pub struct ItemSet {
+    /// `PAM_SERVICE`
+    pub service: Option<String>,
+    /// `PAM_USER`
+    pub user: Option<String>,
+    /// `PAM_USER_PROMPT`
+    pub user_prompt: Option<String>,
+    /// `PAM_TTY`
+    pub tty: Option<String>,
+    /// `PAM_RUSER`
+    pub ruser: Option<String>,
+    /// `PAM_RHOST`
+    pub rhost: Option<String>,
+}
+
+impl ItemSet {
+    // The items in the order they are set
+    fn items(&self) -> [(PamItemType, Option<&str>); 6] {
+        [
+            (PamItemType::Service, self.service.as_deref()),
+            (PamItemType::User, self.user.as_deref()),
+            (PamItemType::User_Prompt, self.user_prompt.as_deref()),
+            (PamItemType::TTY, self.tty.as_deref()),
+            (PamItemType::RUser, self.ruser.as_deref()),
+            (PamItemType::RHost, self.rhost.as_deref()),
+        ]
+    }
+}
+
+/// The result of `Client::authenticate_retry`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthOutcome {
+    /// The result of the last attempt
+    ///
+    /// A module giving up itself (e.g. after too many failures, which may mean a lockout
+    /// persisting across transactions) is reported as `PAM_MAXTRIES` here.
+    pub code: PamReturnCode,
+    /// The number of calls to `pam_authenticate`
+    pub attempts: usize,
+    /// Whether `authenticate_retry` gave up, as all `max_attempts` attempts failed with
+    /// wrong credentials (`code` is the result of the last one)
+    pub exhausted: bool,
+    /// The number of conversation rounds over all attempts, i.e. how often PAM called the
+    /// conversation (modules retrying internally only show up here)
+    pub rounds: usize,
+    /// All informational and error messages shown during the attempts, in order
+    pub messages: Vec<(PamMessageStyle, CString)>,
+}
+
+impl AuthOutcome {
+    /// Whether the authentication finally succeeded
+    pub fn is_success(&self) -> bool {
+        self.code == PamReturnCode::Success
+    }
+
+    /// The expiry of the password announced in `messages`, see `parse_expiry_notice`
+    pub fn expiry(&self) -> Option<ExpiryInfo> {
+        find_expiry(&self.messages)
+    }
+}
+
+/// A successful login, see `Client::try_login`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginOk {
+    /// All informational and error messages shown during the login, in order, e.g.
+    /// warnings about the password expiring soon
+    pub messages: Vec<(PamMessageStyle, CString)>,
+}
+
+impl LoginOk {
+    /// The expiry of the password announced in `messages`, see `parse_expiry_notice`
+    ///
+    /// ```no_run
+    /// # use pam_f::{Client, ExpiryInfo};
+    /// # let mut client = Client::with_password("system-auth").unwrap();
+    /// let login = client.try_login()?;
+    /// if let Some(ExpiryInfo::ExpiresIn { days }) = login.expiry() {
+    ///     println!("Your password expires in {} days", days);
+    /// }
+    /// # Ok::<(), pam_f::LoginError>(())
+    /// ```
+    pub fn expiry(&self) -> Option<ExpiryInfo> {
+        find_expiry(&self.messages)
+    }
+}
+
+// The first expiry notice among `messages`
+fn find_expiry(messages: &[(PamMessageStyle, CString)]) -> Option<ExpiryInfo> {
+    messages
+        .iter()
+        .find_map(|(_, msg)| crate::parse_expiry_notice(msg))
+}
+
+/// Allows resuming a login which requires a password change, see `Client::resume_login`
+///
+/// It can only be obtained from `LoginError::PasswordExpired`.
+#[derive(Debug)]
+pub struct PasswordChange {
+    _private: (),
+}
+
+/// The reason a login failed, see `Client::try_login`
+#[derive(Debug)]
+pub enum LoginError {
+    /// The credentials were wrong or insufficient (`PamCategory::UserError`), retrying
+    /// might help
+    BadCredentials(PamReturnCode),
+    /// The account may not log in (`PamCategory::AccountError`, e.g. `PAM_MAXTRIES` or
+    /// `PAM_ACCT_EXPIRED`), retrying won't help but the user should be told why
+    AccountLocked(PamReturnCode),
+    /// The password is correct but has expired, change it with `Client::resume_login`
+    PasswordExpired(PasswordChange),
+    /// PAM, a module or the system failed (`PamCategory::SystemError`)
+    SystemError(PamReturnCode),
+}
+
+impl LoginError {
+    /// The return code behind this error
+    pub fn code(&self) -> PamReturnCode {
+        match self {
+            LoginError::BadCredentials(code)
+            | LoginError::AccountLocked(code)
+            | LoginError::SystemError(code) => *code,
+            LoginError::PasswordExpired(_) => PamReturnCode::New_Authtok_Reqd,
+        }
+    }
+
+    fn from_code(code: PamReturnCode) -> LoginError {
+        match code {
+            PamReturnCode::New_Authtok_Reqd => {
+                LoginError::PasswordExpired(PasswordChange { _private: () })
+            }
+            code => match code.category() {
+                PamCategory::UserError => LoginError::BadCredentials(code),
+                PamCategory::AccountError => LoginError::AccountLocked(code),
+                // Success is no error either
+                PamCategory::SystemError | PamCategory::Success => LoginError::SystemError(code),
+            },
+        }
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            LoginError::BadCredentials(_) => "bad credentials",
+            LoginError::AccountLocked(_) => "account locked",
+            LoginError::PasswordExpired(_) => "password expired",
+            LoginError::SystemError(_) => "system error",
+        };
+        write!(f, "{}: {}", reason, self.code())
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+impl From<LoginError> for PamError {
+    fn from(err: LoginError) -> PamError {
+        PamError(err.code())
+    }
+}
+
+// Wraps the conversation handler of a `Client` to observe the conversation
+struct Tracked<C> {
+    inner: C,
+    rounds: usize,
+    // Only collected while `Some`
+    messages: Option<Vec<(PamMessageStyle, CString)>>,
+}
+
+impl<C> Tracked<C> {
+    fn new(inner: C) -> Tracked<C> {
+        Tracked {
+            inner,
+            rounds: 0,
+            messages: None,
+        }
+    }
+}
+
+impl<C: conv::Conversation> conv::Conversation for Tracked<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_radio(msg)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.inner.binary_prompt(data)
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        self.rounds += 1;
+        self.inner.begin_batch(num_msg)
+    }
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        self.inner.supported_styles()
+    }
+    fn forget_authtok(&mut self) {
+        self.inner.forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        self.inner.forget_credentials()
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[conv::RawMessage],
+    ) -> Result<Vec<Option<conv::Response>>, PamReturnCode> {
+        if let Some(collected) = self.messages.as_mut() {
+            for m in messages {
+                if let (
+                    PamMessageStyle::Text_Info | PamMessageStyle::Error_Msg,
+                    conv::MessageContent::Text(text),
+                ) = (m.style, m.content)
+                {
+                    collected.push((m.style, text.to_owned()));
+                }
+            }
+        }
+        self.inner.converse_batch(messages)
+    }
 }
 
 impl<'a> Client<'a, conv::PasswordConv> {
@@ -44,38 +298,239 @@ impl<'a> Client<'a, conv::PasswordConv> {
     pub fn with_password(service: &str) -> PamResult<Client<'a, conv::PasswordConv>> {
         Client::with_conversation(service, conv::PasswordConv::new())
     }
+
+    /// Authenticate `user` with an already obtained authentication token (e.g. a password
+    /// collected out-of-band)
+    ///
+    /// This sets the `PAM_USER` and `PAM_AUTHTOK` items before running `authenticate`, so
+    /// that modules which honor a cached token (like `pam_unix` with `use_first_pass` or
+    /// `try_first_pass`) do not prompt at all. For modules which prompt anyway, the
+    /// credentials are also stored in the `PasswordConv`, which answers the prompts.
+    ///
+    /// Caveats:
+    /// - Linux-PAM only allows modules to set `PAM_AUTHTOK` and answers applications with
+    ///   `PAM_BAD_ITEM`. This error is ignored, in which case all modules prompt via the
+    ///   conversation.
+    /// - Whether a module uses a cached token depends entirely on its configuration in the
+    ///   service file.
+    pub fn authenticate_with_token(&mut self, user: &str, token: &str) -> PamResult<()> {
+        self.conversation.inner.set_credentials(user, token);
+        self.set_item_str(PamItemType::User, user)?;
+        match self.set_item_str(PamItemType::AuthTok, token) {
+            Ok(()) | Err(PamError(PamReturnCode::Bad_Item)) => {}
+            Err(err) => return Err(err),
+        }
+        self.authenticate()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> Client<'a, conv::MiscConv> {
+    /// Create a new `Client` with the given service name and the terminal conversation
+    /// `misc_conv` provided by libpam_misc
+    pub fn with_misc_conv(service: &str) -> PamResult<Client<'a, conv::MiscConv>> {
+        // PAM calls `misc_conv` directly, so the conversation cannot be observed
+        let conversation = Box::new(Tracked::new(conv::MiscConv));
+        Client::start_with(service, conversation, &conv::misc_pam_conv())
+    }
 }
 
 impl<'a, C: conv::Conversation> Client<'a, C> {
     /// Create a new `Client` with the given service name and conversation handler
     pub fn with_conversation(service: &str, conversation: C) -> PamResult<Client<'a, C>> {
-        let mut conversation = Box::new(conversation);
+        let mut conversation = Box::new(Tracked::new(conversation));
         let conv = conv::into_pam_conv(&mut *conversation);
+        Client::start_with(service, conversation, &conv)
+    }
+
+    /// Create a new `Client` reading the configuration of `service` from `confdir`
+    ///
+    /// Meant for testing against a fixed stack (`test-util` feature), see `start_confdir`
+    /// for the requirements.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_confdir(
+        service: &str,
+        confdir: &Path,
+        conversation: C,
+    ) -> PamResult<Client<'a, C>> {
+        let mut conversation = Box::new(Tracked::new(conversation));
+        let conv = conv::into_pam_conv(&mut *conversation);
+        let handle = start_confdir(service, None, &conv, confdir)?;
+        Ok(Client::started(service, conversation, handle))
+    }
+
+    // Start the PAM transaction with an already prepared `pam_conv`
+    fn start_with(
+        service: &str,
+        conversation: Box<Tracked<C>>,
+        conv: &ffi::pam_conv,
+    ) -> PamResult<Client<'a, C>> {
+        let handle = start(service, None, conv)?;
+        Ok(Client::started(service, conversation, handle))
+    }
 
-        let handle = start(service, None, &conv)?;
-        Ok(Client {
+    // Wrap the handle of a started transaction, which uses `conversation`
+    fn started(
+        service: &str,
+        conversation: Box<Tracked<C>>,
+        handle: &'a mut PamHandle,
+    ) -> Client<'a, C> {
+        Client {
             close_on_drop: true,
+            service: service.to_string(),
             handle,
             conversation,
             is_authenticated: false,
+            new_authtok_required: false,
             has_open_session: false,
+            credentials_deleted: false,
             last_code: PamReturnCode::Success,
-        })
+            silent: false,
+            handed_off: false,
+            session_env: None,
+            #[cfg(feature = "syslog")]
+            audit_log: None,
+        }
     }
 
     /// Immutable access to the conversation handler of this Client
     pub fn conversation(&self) -> &C {
-        &self.conversation
+        &self.conversation.inner
     }
 
     /// Mutable access to the conversation handler of this Client
     pub fn conversation_mut(&mut self) -> &mut C {
-        &mut self.conversation
+        &mut self.conversation.inner
+    }
+
+    /// Replace the conversation handler for all following PAM calls
+    ///
+    /// This sets the `PAM_CONV` item to the new handler and returns the previous one. PAM
+    /// keeps a pointer to the handler until the transaction ends, which is why the
+    /// `Client` takes ownership of it (behind a `Box`, to keep its address stable). To
+    /// switch between different types of handlers (e.g. from a non-interactive one to an
+    /// interactive one for `change_authentication_token`), use a `Client` with a
+    /// `Box<dyn Conversation>`.
+    ///
+    /// If setting the item fails, the previous handler stays in place.
+    pub fn set_conversation(&mut self, conversation: C) -> PamResult<C> {
+        let mut conversation = Box::new(Tracked::new(conversation));
+        let conv = conv::into_pam_conv(&mut *conversation);
+        let item = &conv as *const ffi::pam_conv as *const libc::c_void;
+        // PAM copies the `pam_conv` struct itself
+        set_item(self.handle, PamItemType::Conv, unsafe { &*item })?;
+        Ok(std::mem::replace(&mut self.conversation, conversation).inner)
+    }
+
+    /// Run `authenticate` with `conversation` instead of the handler of this `Client`
+    ///
+    /// `conversation` is set as the `PAM_CONV` item for the duration of this call only, the
+    /// previous one is restored afterwards. This allows e.g. an interactive handler for the
+    /// authentication and a silent one for everything else on a long-lived `Client`.
+    /// Conversation rounds and messages of `conversation` are not tracked.
+    pub fn authenticate_with<D: conv::Conversation>(
+        &mut self,
+        conversation: &mut D,
+    ) -> PamResult<()> {
+        self.with_temporary_conversation(conversation, |client| client.authenticate())
+    }
+
+    /// Run `change_authentication_token` with `conversation` instead of the handler of this
+    /// `Client`, see `authenticate_with`
+    pub fn change_authentication_token_with<D: conv::Conversation>(
+        &mut self,
+        conversation: &mut D,
+        flags: PamFlag,
+    ) -> PamResult<()> {
+        self.with_temporary_conversation(conversation, |client| {
+            client.change_authentication_token(flags)
+        })
+    }
+
+    // Run `f` with `conversation` as the `PAM_CONV` item, then restore the previous item
+    //
+    // `conversation` is only borrowed, so PAM must not keep a pointer to it afterwards: the
+    // previous item is restored by a guard, also if `f` panics, see `RestoreConv`.
+    fn with_temporary_conversation<D: conv::Conversation>(
+        &mut self,
+        conversation: &mut D,
+        f: impl FnOnce(&mut Self) -> PamResult<()>,
+    ) -> PamResult<()> {
+        let previous = match get_item(self.handle, PamItemType::Conv)? {
+            Some(item) => {
+                let item = unsafe { &*(item as *const libc::c_void as *const ffi::pam_conv) };
+                ffi::pam_conv {
+                    conv: item.conv,
+                    appdata_ptr: item.appdata_ptr,
+                }
+            }
+            None => return Err(PamReturnCode::System_Err.into()),
+        };
+        let conv = conv::into_pam_conv(conversation);
+        let item = &conv as *const ffi::pam_conv as *const libc::c_void;
+        // PAM copies the `pam_conv` struct itself
+        set_item(self.handle, PamItemType::Conv, unsafe { &*item })?;
+
+        let guard = RestoreConv {
+            client: self,
+            previous,
+        };
+        f(&mut *guard.client)
+    }
+
+    /// Pass `PAM_SILENT` to all subsequent PAM calls to suppress status messages of modules
+    ///
+    /// Note that this is merely a request and some modules ignore it.
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
     }
 
     /// Perform authentication with the provided credentials
+    ///
+    /// If the account management reports an expired authentication token
+    /// (`PAM_NEW_AUTHTOK_REQD`), this returns that error but keeps the transaction as is,
+    /// so the token can be changed right away with `change_authentication_token`:
+    ///
+    /// ```no_run
+    /// use pam_f::{Client, PamError, PamFlag, PamReturnCode};
+    ///
+    /// let mut client = Client::with_password("system-auth").unwrap();
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// match client.authenticate() {
+    ///     Err(PamError(PamReturnCode::New_Authtok_Reqd)) => {
+    ///         // Same handle, so modules can reuse the old token they already got
+    ///         client
+    ///             .change_authentication_token(PamFlag::Change_Expired_AuthTok)
+    ///             .expect("Failed to change the password!");
+    ///     }
+    ///     result => result.expect("Authentication failed!"),
+    /// }
+    /// // Authenticated now
+    /// client.open_session().expect("Failed to open a session!");
+    /// ```
+    ///
+    /// Starting a new `Client` instead would lose the state of the modules and prompt for
+    /// the old password again.
     pub fn authenticate(&mut self) -> PamResult<()> {
-        self.last_code = authenticate(self.handle, PamFlag::None);
+        let result = self.authenticate_and_check();
+        #[cfg(feature = "syslog")]
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.log_authentication(self.handle, self.last_code);
+        }
+        result
+    }
+
+    /// Log the result of each `authenticate` to syslog, or stop doing so with `None`
+    ///
+    /// See `AuditLog` for the contents of the entries. Disabled by default.
+    #[cfg(feature = "syslog")]
+    pub fn set_audit_log(&mut self, audit_log: Option<crate::AuditLog>) {
+        self.audit_log = audit_log;
+    }
+
+    fn authenticate_and_check(&mut self) -> PamResult<()> {
+        self.new_authtok_required = false;
+        self.last_code = authenticate(self.handle, self.flags(PamFlag::None));
         if self.last_code != PamReturnCode::Success {
             // No need to reset here
             return Err(From::from(self.last_code));
@@ -83,35 +538,283 @@ impl<'a, C: conv::Conversation> Client<'a, C> {
 
         self.is_authenticated = true;
 
-        self.last_code = acct_mgmt(self.handle, PamFlag::None);
-        if self.last_code != PamReturnCode::Success {
+        let code = acct_mgmt(self.handle, self.flags(PamFlag::None));
+        self.account_checked(code)
+    }
+
+    /// Run `authenticate` up to `max_attempts` times, until it succeeds
+    ///
+    /// Only failures because of wrong credentials (see `PamCategory::UserError`) are
+    /// retried; the conversation handler is asked again for each attempt, so this is
+    /// meant for interactive handlers. Returns how many attempts and conversation rounds it
+    /// took and the messages shown meanwhile, e.g. for lockout accounting or audit logs.
+    /// Use `max_attempts` of 1 to get these for a single attempt.
+    ///
+    /// Conversation rounds and messages are not observable with `Client::with_misc_conv`.
+    pub fn authenticate_retry(&mut self, max_attempts: NonZeroUsize) -> AuthOutcome {
+        self.conversation.rounds = 0;
+        self.conversation.messages = Some(Vec::new());
+        let (code, attempts, exhausted) = retry(max_attempts, || match self.authenticate() {
+            Ok(()) => PamReturnCode::Success,
+            Err(err) => err.0,
+        });
+        AuthOutcome {
+            code,
+            attempts,
+            exhausted,
+            rounds: self.conversation.rounds,
+            messages: self.conversation.messages.take().unwrap_or_default(),
+        }
+    }
+
+    /// Authenticate and check the account, classifying the outcome
+    ///
+    /// This is `authenticate` with the result sorted into what an application usually has
+    /// to tell apart. Messages shown meanwhile are returned on success, they are not
+    /// observable with `Client::with_misc_conv`.
+    ///
+    /// ```no_run
+    /// # use pam_f::{Client, LoginError};
+    /// let mut client =
+    ///     Client::with_password("system-auth").expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// let login = match client.try_login() {
+    ///     Err(LoginError::PasswordExpired(change)) => client.resume_login(change),
+    ///     result => result,
+    /// };
+    /// match login {
+    ///     Ok(_) => client.open_session().expect("Failed to open a session!"),
+    ///     Err(LoginError::BadCredentials(_)) => eprintln!("Wrong login or password"),
+    ///     Err(err) => eprintln!("Login failed: {}", err),
+    /// }
+    /// ```
+    pub fn try_login(&mut self) -> Result<LoginOk, LoginError> {
+        self.conversation.messages = Some(Vec::new());
+        let result = self.authenticate();
+        self.login_result(result)
+    }
+
+    /// Change the expired password and complete the login started by `try_login`
+    ///
+    /// Runs `change_authentication_token` with `PamFlag::Change_Expired_AuthTok` on the
+    /// same handle. If this fails, `requires_password_change` stays true, so the change
+    /// can still be retried with `change_authentication_token`.
+    pub fn resume_login(&mut self, change: PasswordChange) -> Result<LoginOk, LoginError> {
+        let PasswordChange { _private: () } = change;
+        self.conversation.messages = Some(Vec::new());
+        let result = self.change_authentication_token(PamFlag::Change_Expired_AuthTok);
+        self.login_result(result)
+    }
+
+    fn login_result(&mut self, result: PamResult<()>) -> Result<LoginOk, LoginError> {
+        let messages = self.conversation.messages.take().unwrap_or_default();
+        match result {
+            Ok(()) => Ok(LoginOk { messages }),
+            Err(err) => Err(LoginError::from_code(err.0)),
+        }
+    }
+
+    /// Like `authenticate`, but takes at least `min` regardless of the outcome
+    ///
+    /// Sleeps out the remainder of `min` after `authenticate` returned, so that e.g. an
+    /// unknown user cannot be told apart from a wrong password by how long the attempt
+    /// took. This is a mitigation, not a guarantee: `min` has to exceed the slowest
+    /// attempt, otherwise the differences beyond it still leak.
+    ///
+    /// Note that PAM delays failures itself (see `pam_fail_delay` and e.g. the `nodelay`
+    /// option of `pam_unix`), which is included in the time measured here.
+    pub fn authenticate_constant_time(&mut self, min: Duration) -> PamResult<()> {
+        with_min_duration(min, || self.authenticate())
+    }
+
+    // Utility function to handle the result of `acct_mgmt` after a successful
+    // authentication
+    fn account_checked(&mut self, code: PamReturnCode) -> PamResult<()> {
+        self.last_code = code;
+        match code {
+            PamReturnCode::Success => Ok(()),
+            PamReturnCode::New_Authtok_Reqd => {
+                // Keep the credentials and the state of the modules for `chauthtok`, but
+                // do not allow opening a session until the token has been changed
+                self.is_authenticated = false;
+                self.new_authtok_required = true;
+                Err(From::from(code))
+            }
             // Probably not strictly neccessary but better be sure
-            return self.reset();
+            _ => self.reset(),
         }
-        Ok(())
     }
 
     /// Perform the chauthtok to support password update
+    ///
+    /// If `authenticate` failed with `PAM_NEW_AUTHTOK_REQD` before, a successful change
+    /// completes the authentication, see `authenticate`.
     pub fn change_authentication_token(&mut self, flags: PamFlag) -> PamResult<()> {
-        self.last_code = chauthtok(self.handle, flags);
+        let code = chauthtok(self.handle, self.flags(flags));
+        self.authtok_changed(code)
+    }
+
+    // Utility function to handle the result of `chauthtok`
+    fn authtok_changed(&mut self, code: PamReturnCode) -> PamResult<()> {
+        self.last_code = code;
         if self.last_code != PamReturnCode::Success {
             // No need to reset here
             return Err(From::from(self.last_code));
         }
+        if self.new_authtok_required {
+            self.new_authtok_required = false;
+            self.is_authenticated = true;
+        }
+        Ok(())
+    }
+
+    /// Whether the last `authenticate` failed because the token has to be changed first
+    ///
+    /// This is the case if the account management returned `PAM_NEW_AUTHTOK_REQD` and the
+    /// token has not been changed successfully since, see `change_authentication_token`.
+    ///
+    /// ```no_run
+    /// # use pam_f::{Client, PamFlag};
+    /// # let mut client = Client::with_password("system-auth").unwrap();
+    /// if client.authenticate().is_err() && client.requires_password_change() {
+    ///     client.change_authentication_token(PamFlag::Change_Expired_AuthTok)?;
+    /// }
+    /// # Ok::<(), pam_f::PamError>(())
+    /// ```
+    pub fn requires_password_change(&self) -> bool {
+        self.new_authtok_required
+    }
+
+    /// Set the string item `item_type` (e.g. `PamItemType::RHost`) from a Rust string
+    ///
+    /// Fails with `PAM_BUF_ERR` if `value` contains a nul byte.
+    pub fn set_item_str(&mut self, item_type: PamItemType, value: &str) -> PamResult<()> {
+        set_item_str(self.handle, item_type, value)
+    }
+
+    /// Set all items present in `items`
+    ///
+    /// Stops at the first item which cannot be set and returns which one it was. The
+    /// items set before are not reverted.
+    pub fn set_items(&mut self, items: &ItemSet) -> Result<(), ItemError> {
+        for (item, value) in items.items().iter() {
+            if let Some(value) = value {
+                self.set_item_str(*item, value)
+                    .map_err(|error| ItemError { item: *item, error })?;
+            }
+        }
         Ok(())
     }
 
+    /// Set the string item `item_type` (e.g. `PamItemType::RHost`) from a C string
+    ///
+    /// PAM copies the string, so no additional allocation is necessary.
+    pub fn set_item_cstr(&mut self, item_type: PamItemType, value: &CStr) -> PamResult<()> {
+        set_item_cstr(self.handle, item_type, value)
+    }
+
+    /// The current value of the string item `item_type` (e.g. `PamItemType::RHost`)
+    ///
+    /// Modules may change items during the stack (e.g. canonicalize the remote host), so
+    /// this can differ from the value set before. Returns `None` if the item is not set
+    /// and fails with `PAM_SYSTEM_ERR` if it is not valid UTF-8.
+    pub fn get_item_str(&self, item_type: PamItemType) -> PamResult<Option<String>> {
+        match get_item_cstr(self.handle, item_type)? {
+            None => Ok(None),
+            Some(value) => match value.to_str() {
+                Err(_) => Err(PamError(PamReturnCode::System_Err)),
+                Ok(value) => Ok(Some(value.to_string())),
+            },
+        }
+    }
+
+    /// The current remote host (`PAM_RHOST`), e.g. for audit logs, see `get_item_str`
+    pub fn get_rhost(&self) -> PamResult<Option<String>> {
+        self.get_item_str(PamItemType::RHost)
+    }
+
+    /// The current remote user (`PAM_RUSER`), e.g. for audit logs, see `get_item_str`
+    pub fn get_ruser(&self) -> PamResult<Option<String>> {
+        self.get_item_str(PamItemType::RUser)
+    }
+
+    /// Check whether the PAM implementation knows the item `item_type`
+    ///
+    /// See `supports_item` for details.
+    pub fn supports_item(&self, item_type: PamItemType) -> bool {
+        probe::supports_item(self.handle, item_type)
+    }
+
     /// Perform the get_item / PAM_USER to retrive the username
+    ///
+    /// Fails with `PAM_USER_UNKNOWN` if no user has been set yet.
     pub fn get_user(&mut self) -> PamResult<String> {
-        get_item(self.handle, PamItemType::User).and_then(|result| {
-            // Pam user is a char *
-            let ptr: *const c_char = result as *const libc::c_void as *const i8;
-            let username = unsafe { CStr::from_ptr(ptr) };
-            match username.to_str() {
+        match get_item_cstr(self.handle, PamItemType::User)? {
+            None => Err(PamError(PamReturnCode::User_Unknown)),
+            Some(username) => match username.to_str() {
                 Err(_) => Err(PamError(PamReturnCode::System_Err)),
                 Ok(username) => Ok(username.to_string()),
+            },
+        }
+    }
+
+    /// The name of the service as known to PAM, see `get_service`
+    ///
+    /// This differs from the name the `Client` was created with if `PAM_SERVICE` was
+    /// changed since, e.g. with `set_items`.
+    pub fn get_service(&self) -> Option<String> {
+        get_service(self.handle)
+    }
+
+    /// Clear the cached authentication tokens (`PAM_AUTHTOK` and `PAM_OLDAUTHTOK`)
+    ///
+    /// Use this between steps of a multi-step flow, so a token obtained for one step is
+    /// not reused by modules in the next one (e.g. with `use_first_pass`). The conversation
+    /// handler is asked to forget its tokens as well, see
+    /// `Conversation::forget_authtok`.
+    ///
+    /// Linux-PAM only allows modules to change these items and answers applications with
+    /// `PAM_BAD_ITEM`, which is ignored: it clears the tokens itself when
+    /// `pam_authenticate` and `pam_chauthtok` return, so they never outlive the call which
+    /// obtained them there.
+    pub fn clear_authtok(&mut self) -> PamResult<()> {
+        self.conversation.inner.forget_authtok();
+        for &item in &[PamItemType::AuthTok, PamItemType::OldAuthTok] {
+            match clear_item(self.handle, item) {
+                Ok(()) | Err(PamError(PamReturnCode::Bad_Item)) => {}
+                Err(err) => return Err(err),
             }
-        })
+        }
+        Ok(())
+    }
+
+    /// Forget the user and the authentication tokens, so the next `authenticate` asks again
+    ///
+    /// This clears `PAM_USER` as well as the tokens (see `clear_authtok`) and asks the
+    /// conversation handler to forget its credentials, see
+    /// `Conversation::forget_credentials`. With `PasswordConv`, set new credentials before
+    /// authenticating again. Useful for retrying with different credentials on the same
+    /// handle, e.g. for testing a failed login followed by a successful one.
+    pub fn forget_credentials(&mut self) -> PamResult<()> {
+        self.conversation.inner.forget_credentials();
+        self.clear_authtok()?;
+        clear_item(self.handle, PamItemType::User)
+    }
+
+    /// The uid and gid of the authenticated user, if provided by PAM
+    ///
+    /// This only reports what a module exported as `UID` and `GID` into the PAM
+    /// environment and never falls back to looking up the user (e.g. via `getpwnam`).
+    /// Most stacks don't provide this information, so `None` (which is also returned
+    /// before a successful `authenticate`) means the caller has to resolve the user
+    /// itself, e.g. before dropping the privileges needed for NSS lookups.
+    pub fn authenticated_uid(&mut self) -> Option<(u32, u32)> {
+        if !self.is_authenticated {
+            return None;
+        }
+        let uid = getenv(self.handle, "UID").ok()??.parse().ok()?;
+        let gid = getenv(self.handle, "GID").ok()??.parse().ok()?;
+        Some((uid, gid))
     }
 
     /// Open a session for a previously authenticated user and
@@ -122,24 +825,91 @@ impl<'a, C: conv::Conversation> Client<'a, C> {
             return Err(PamReturnCode::Perm_Denied.into());
         }
 
-        self.last_code = setcred(self.handle, PamFlag::Establish_Cred);
+        self.last_code = setcred(self.handle, self.flags(PamFlag::Establish_Cred));
         if self.last_code != PamReturnCode::Success {
             return self.reset();
         }
+        self.credentials_deleted = false;
 
-        self.last_code = open_session(self.handle, false);
+        self.last_code = open_session(self.handle, self.silent);
         if self.last_code != PamReturnCode::Success {
             return self.reset();
         }
 
         // Follow openSSH and call pam_setcred before and after open_session
-        self.last_code = setcred(self.handle, PamFlag::Reinitialize_Cred);
+        self.last_code = setcred(self.handle, self.flags(PamFlag::Reinitialize_Cred));
         if self.last_code != PamReturnCode::Success {
             return self.reset();
         }
 
         self.has_open_session = true;
-        self.initialize_environment()
+        self.initialize_environment()?;
+
+        self.session_env = Some(getenvlist(self.handle).collect());
+        Ok(())
+    }
+
+    /// Close the session opened by `open_session` and delete the credentials
+    ///
+    /// This is what dropping the `Client` does anyway, but allows handling errors. It is
+    /// safe to call this multiple times: once the session has been closed successfully,
+    /// neither further calls nor the drop call `pam_close_session` again, and the
+    /// credentials are deleted only once as well. If closing fails, the session is
+    /// still considered open and the drop tries again (if `close_on_drop` is set).
+    pub fn close_session(&mut self) -> PamResult<()> {
+        self.last_code = self.close_open_session();
+        if self.last_code != PamReturnCode::Success {
+            return Err(self.last_code.into());
+        }
+        match self.delete_credentials() {
+            PamReturnCode::Success => Ok(()),
+            code => Err(code.into()),
+        }
+    }
+
+    /// Whether a session has been opened by `open_session` and not been closed since
+    ///
+    /// This is `false` if `open_session` failed and after a successful `close_session`, and
+    /// stays `true` after `hand_off`. It tells whether dropping the `Client` (with
+    /// `close_on_drop` set) closes a session.
+    pub fn is_session_open(&self) -> bool {
+        self.has_open_session
+    }
+
+    /// The PAM environment of the session opened by `open_session`
+    ///
+    /// This is a snapshot taken at the very end of `open_session`, i.e. after
+    /// `pam_open_session`, the following `pam_setcred` to reinitialize the credentials and
+    /// after setting the common variables (`USER`, `HOME`, ...). It contains all variables
+    /// exported by modules (e.g. `XDG_SESSION_ID` or `KRB5CCNAME`) and is meant to be
+    /// passed to the user's shell. Variables changed later on are not reflected.
+    ///
+    /// Returns `None` if no session has been opened.
+    pub fn session_env(&self) -> Option<&[(String, String)]> {
+        self.session_env.as_deref()
+    }
+
+    /// Copy the current PAM environment into the process environment
+    pub fn paste_env(&mut self) {
+        for (key, value) in getenvlist(self.handle) {
+            env::set_var(key, value);
+        }
+    }
+
+    /// Hand off the PAM transaction to another process
+    ///
+    /// After a `fork`, both processes hold a copy of the PAM handle, but only one of them
+    /// may run the cleanup of the modules. Call this in the process giving up its copy:
+    /// dropping this `Client` then neither closes the session nor deletes the credentials
+    /// and ends the transaction with `PAM_DATA_SILENT`, so the modules only release their
+    /// memory.
+    ///
+    /// A typical login program authenticates, opens the session and forks. The child calls
+    /// this method and drops its `Client` right before it execs the user's shell (`exec`
+    /// runs no destructors, so the `Client` has to be dropped explicitly). The parent waits
+    /// for the child to exit and then closes the session, e.g. by dropping its `Client`.
+    pub fn hand_off(&mut self) {
+        self.handed_off = true;
     }
 
     // Initialize the client environment with common variables.
@@ -185,20 +955,1046 @@ impl<'a, C: conv::Conversation> Client<'a, C> {
         }
     }
 
+    // Utility function to summarize the state of the transaction
+    fn state(&self) -> &'static str {
+        if self.handed_off {
+            "handed off"
+        } else if self.has_open_session {
+            "session open"
+        } else if self.is_authenticated {
+            "authenticated"
+        } else if self.new_authtok_required {
+            "new authentication token required"
+        } else {
+            "started"
+        }
+    }
+
+    // Utility function to combine `flag` with the flags common to all calls
+    fn flags(&self, flag: PamFlag) -> libc::c_int {
+        if self.silent {
+            flag as libc::c_int | PamFlag::Silent as libc::c_int
+        } else {
+            flag as libc::c_int
+        }
+    }
+
+    // Utility function to close the session, if it is open
+    fn close_open_session(&mut self) -> PamReturnCode {
+        if !self.has_open_session {
+            return PamReturnCode::Success;
+        }
+        let code = close_session(self.handle, self.silent);
+        if code == PamReturnCode::Success {
+            self.has_open_session = false;
+            self.session_env = None;
+        }
+        code
+    }
+
+    // Utility function to delete the credentials, unless this already happened
+    fn delete_credentials(&mut self) -> PamReturnCode {
+        if self.credentials_deleted {
+            return PamReturnCode::Success;
+        }
+        let code = setcred(self.handle, self.flags(PamFlag::Delete_Cred));
+        if code == PamReturnCode::Success {
+            self.credentials_deleted = true;
+        }
+        code
+    }
+
     // Utility function to reset the pam handle in case of intermediate errors
     fn reset(&mut self) -> PamResult<()> {
-        setcred(self.handle, PamFlag::Delete_Cred);
+        self.delete_credentials();
         self.is_authenticated = false;
         Err(From::from(self.last_code))
     }
 }
 
+// Restores the `PAM_CONV` item of `client` to `previous` when dropped
+//
+// PAM would be left with a dangling pointer to a temporary conversation handler if this
+// failed, so the process is aborted in that case, as no transaction can continue safely.
+struct RestoreConv<'c, 'a, C: conv::Conversation> {
+    client: &'c mut Client<'a, C>,
+    previous: ffi::pam_conv,
+}
+
+impl<C: conv::Conversation> Drop for RestoreConv<'_, '_, C> {
+    fn drop(&mut self) {
+        let item = &self.previous as *const ffi::pam_conv as *const libc::c_void;
+        if set_item(self.client.handle, PamItemType::Conv, unsafe { &*item }).is_err() {
+            eprintln!("[PAM ERROR] Failed to restore the conversation handler, aborting");
+            std::process::abort();
+        }
+    }
+}
+
+// Call `attempt` up to `max_attempts` times while it fails with a `UserError`, returns the
+// last result, the number of calls and whether all of them failed that way
+fn retry(
+    max_attempts: NonZeroUsize,
+    mut attempt: impl FnMut() -> PamReturnCode,
+) -> (PamReturnCode, usize, bool) {
+    let max_attempts = max_attempts.get();
+    let mut attempts = 1;
+    loop {
+        let code = attempt();
+        if code.category() != PamCategory::UserError {
+            return (code, attempts, false);
+        }
+        if attempts == max_attempts {
+            return (code, attempts, true);
+        }
+        attempts += 1;
+    }
+}
+
+// Run `f` and sleep until at least `min` has passed since starting it
+fn with_min_duration<T>(min: Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    if let Some(remaining) = min.checked_sub(start.elapsed()) {
+        thread::sleep(remaining);
+    }
+    result
+}
+
+/// Prints the service, the state of the transaction and the type of the conversation
+/// handler only, never the contents of the conversation handler or any items (like
+/// `PAM_AUTHTOK`), so it is safe to log.
+impl<C: conv::Conversation> fmt::Debug for Client<'_, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("service", &self.service)
+            .field("state", &self.state())
+            .field("last_code", &self.last_code)
+            .field("silent", &self.silent)
+            .field("close_on_drop", &self.close_on_drop)
+            .field("conversation", &any::type_name::<C>())
+            .finish()
+    }
+}
+
 impl<C: conv::Conversation> Drop for Client<'_, C> {
     fn drop(&mut self) {
-        if self.has_open_session && self.close_on_drop {
-            close_session(self.handle, false);
+        if self.handed_off {
+            end(self.handle, self.last_code, true);
+            return;
+        }
+        if self.close_on_drop {
+            self.close_open_session();
+        }
+        let code = self.delete_credentials();
+        end(self.handle, code, false);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, os::raw::c_void, rc::Rc};
+
+    // A PAM configuration directory with the single service `pam-f-test`, so tests do not
+    // depend on (or change) the configuration of the host. `$DIR` in `config` is replaced
+    // by the directory.
+    pub(crate) struct Fixture {
+        dir: std::path::PathBuf,
+    }
+
+    impl Fixture {
+        pub(crate) fn new(config: &str) -> Fixture {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+            let dir = env::temp_dir().join(format!(
+                "pam-f-fixture-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let config = config.replace("$DIR", dir.to_str().unwrap());
+            std::fs::write(dir.join("pam-f-test"), config).unwrap();
+            Fixture { dir }
+        }
+
+        // Add an executable script, e.g. for `pam_exec`
+        pub(crate) fn script(self, name: &str, script: &str) -> Fixture {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = self.dir.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            self
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.dir
+        }
+
+        pub(crate) fn client<C: conv::Conversation>(&self, conversation: C) -> Client<'static, C> {
+            Client::with_confdir("pam-f-test", self.path(), conversation).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Permits everything
+    pub(crate) const PERMIT: &str = "\
+auth required pam_permit.so
+account required pam_permit.so
+password required pam_permit.so
+session required pam_permit.so
+";
+
+    // Asks for the user and the password, and accepts only the password `right`. A refused
+    // password prompt fails with the code of the conversation. Changing the password asks
+    // for it as well, but always fails.
+    pub(crate) const PASSWORD: &str = "\
+auth required pam_permit.so
+auth [success=1 authtok_recover_err=bad default=ignore] pam_exec.so quiet expose_authtok $DIR/check
+auth requisite pam_debug.so auth=auth_err
+auth required pam_permit.so
+account required pam_permit.so
+password requisite pam_exec.so quiet expose_authtok $DIR/check
+session required pam_permit.so
+";
+
+    pub(crate) fn password_fixture() -> Fixture {
+        Fixture::new(PASSWORD).script("check", "[ \"$(tr -d '\\000')\" = right ]")
+    }
+
+    // Records the token of each authentication in `tokens`, asking for it unless a token
+    // is cached, then accepts only the cached token `right`
+    const TOKEN: &str = "\
+auth required pam_exec.so quiet expose_authtok $DIR/record
+auth [success=1 default=ignore] pam_exec.so quiet expose_authtok $DIR/check
+auth requisite pam_deny.so
+auth required pam_permit.so
+account required pam_permit.so
+";
+
+    fn token_fixture() -> Fixture {
+        Fixture::new(TOKEN)
+            .script(
+                "record",
+                "{ tr -d '\\000'; echo; } >> \"$(dirname \"$0\")/tokens\"",
+            )
+            .script("check", "[ \"$(tr -d '\\000')\" = right ]")
+    }
+
+    fn recorded_tokens(fixture: &Fixture) -> String {
+        std::fs::read_to_string(fixture.path().join("tokens")).unwrap_or_default()
+    }
+
+    // Shows `You have mail.` when establishing and deleting the credentials of `root`, and
+    // logs opening and closing sessions to `calls`
+    const TEARDOWN: &str = "\
+auth required pam_permit.so
+auth optional pam_mail.so dir=$DIR standard close
+account required pam_permit.so
+session optional pam_exec.so quiet $DIR/log
+session required pam_permit.so
+";
+
+    fn teardown_fixture() -> Fixture {
+        let fixture = Fixture::new(TEARDOWN)
+            .script("log", "echo \"$PAM_TYPE\" >> \"$(dirname \"$0\")/calls\"");
+        std::fs::write(fixture.path().join("root"), "From pam-f\n").unwrap();
+        fixture
+    }
+
+    fn logged_calls(fixture: &Fixture) -> String {
+        std::fs::read_to_string(fixture.path().join("calls")).unwrap_or_default()
+    }
+
+    // Keeps the informational messages where the test can still see them after the
+    // client is gone
+    struct InfoLog(Rc<RefCell<Vec<String>>>);
+
+    impl conv::Conversation for InfoLog {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Err(())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Err(())
+        }
+        fn info(&mut self, msg: &CStr) {
+            self.0.borrow_mut().push(msg.to_string_lossy().into_owned());
+        }
+        fn error(&mut self, _msg: &CStr) {}
+    }
+
+    fn conv_appdata<C: conv::Conversation>(client: &Client<C>) -> *mut c_void {
+        let item = get_item(client.handle, PamItemType::Conv).unwrap().unwrap();
+        let conv = unsafe { &*(item as *const c_void as *const ffi::pam_conv) };
+        conv.appdata_ptr
+    }
+
+    // Shows messages when authenticating, checking the account, establishing and deleting
+    // the credentials of `root`, opening and closing the session and changing the password,
+    // unless PAM_SILENT is set
+    const CHATTY: &str = "\
+auth optional pam_echo.so auth
+auth required pam_permit.so
+auth optional pam_mail.so dir=$DIR standard close
+account optional pam_echo.so account
+account required pam_permit.so
+password optional pam_echo.so password
+password required pam_permit.so
+session optional pam_echo.so session
+session optional pam_mail.so dir=$DIR empty close
+session required pam_permit.so
+";
+
+    #[test]
+    fn silent_flag_is_forwarded() {
+        let fixture = Fixture::new(CHATTY);
+        std::fs::write(fixture.path().join("root"), "From pam-f\n").unwrap();
+        let old_mail = format!(
+            "You have old mail in folder {}/root.",
+            fixture.path().display()
+        );
+
+        for &silent in &[false, true] {
+            let infos = Rc::new(RefCell::new(Vec::new()));
+            let mut client = fixture.client(InfoLog(infos.clone()));
+            client.set_item_str(PamItemType::User, "root").unwrap();
+            client.set_silent(silent);
+            let shown = || infos.borrow_mut().drain(..).collect::<Vec<_>>();
+            client.authenticate().unwrap();
+            let expected = ["auth", "account"];
+            assert_eq!(shown(), if silent { &[][..] } else { &expected[..] });
+
+            // Establishing the credentials and opening the session
+            client.open_session().unwrap();
+            let expected = ["You have mail.", "session", &old_mail];
+            assert_eq!(shown(), if silent { &[][..] } else { &expected[..] });
+            client.change_authentication_token(PamFlag::None).unwrap();
+            assert_eq!(shown(), if silent { &[][..] } else { &["password"][..] });
+            // Closing the session and deleting the credentials
+            client.close_session().unwrap();
+            let expected = [&old_mail, "You have mail."];
+            assert_eq!(shown(), if silent { &[][..] } else { &expected[..] });
+        }
+    }
+
+    fn item_str<C: conv::Conversation>(client: &Client<C>, item_type: PamItemType) -> String {
+        let item = get_item_cstr(client.handle, item_type).unwrap().unwrap();
+        item.to_str().unwrap().to_string()
+    }
+
+    // Uses the configuration of the host, which falls back to `other` for `pam-f-test`
+    #[test]
+    #[ignore]
+    fn host_stack() {
+        let mut client = Client::with_password("pam-f-test").unwrap();
+        assert_eq!(client.get_service().as_deref(), Some("pam-f-test"));
+        client
+            .conversation_mut()
+            .set_credentials("pam-f-nobody", "wrong");
+        assert!(client.authenticate().is_err());
+    }
+
+    #[test]
+    fn set_item_str_and_cstr() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+
+        client
+            .set_item_str(PamItemType::RHost, "example.org")
+            .unwrap();
+        assert_eq!(item_str(&client, PamItemType::RHost), "example.org");
+
+        let host = CString::new("example.com").unwrap();
+        client.set_item_cstr(PamItemType::RHost, &host).unwrap();
+        drop(host);
+        assert_eq!(item_str(&client, PamItemType::RHost), "example.com");
+
+        assert!(client.set_item_str(PamItemType::RHost, "a\0b").is_err());
+        let conv = CString::new("not a pam_conv").unwrap();
+        assert_eq!(
+            client
+                .set_item_cstr(PamItemType::Conv, &conv)
+                .map_err(|e| e.0),
+            Err(PamReturnCode::Bad_Item)
+        );
+    }
+
+    #[test]
+    fn item_support() {
+        let fixture = Fixture::new(PERMIT);
+        let client = fixture.client(conv::PasswordConv::new());
+        assert!(client.supports_item(PamItemType::RHost));
+    }
+
+    #[test]
+    fn authenticated_uid_from_pam_env() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+        putenv(client.handle, "UID=1000").unwrap();
+        putenv(client.handle, "GID=100").unwrap();
+        assert_eq!(client.authenticated_uid(), None);
+
+        client.is_authenticated = true;
+        assert_eq!(client.authenticated_uid(), Some((1000, 100)));
+
+        putenv(client.handle, "GID=users").unwrap();
+        assert_eq!(client.authenticated_uid(), None);
+        putenv(client.handle, "GID").unwrap();
+        assert_eq!(client.authenticated_uid(), None);
+    }
+
+    #[test]
+    fn hand_off_skips_cleanup() {
+        let fixture = teardown_fixture();
+        let infos = Rc::new(RefCell::new(Vec::new()));
+        let open = |fixture: &Fixture| {
+            let mut client = fixture.client(InfoLog(infos.clone()));
+            client.set_item_str(PamItemType::User, "root").unwrap();
+            client.authenticate().unwrap();
+            client.open_session().unwrap();
+            client
+        };
+
+        // The child, before exec
+        let mut child = open(&fixture);
+        infos.borrow_mut().clear();
+        child.hand_off();
+        assert!(format!("{:?}", child).contains("\"handed off\""));
+        drop(child);
+        assert_eq!(logged_calls(&fixture), "open_session\n");
+        assert!(infos.borrow().is_empty());
+
+        // The parent, after the child exited
+        let parent = open(&fixture);
+        infos.borrow_mut().clear();
+        drop(parent);
+        assert_eq!(
+            logged_calls(&fixture),
+            "open_session\nopen_session\nclose_session\n"
+        );
+        assert_eq!(*infos.borrow(), ["You have mail."]);
+    }
+
+    #[test]
+    fn conversation_survives_move() {
+        let fixture = Fixture::new(PERMIT);
+        let client = fixture.client(conv::PasswordConv::new());
+        let before = conv_appdata(&client);
+
+        // Move the client to the heap, as a store of long-lived clients would
+        let client = Box::new(client);
+        assert_eq!(conv_appdata(&client), before);
+        assert_eq!(
+            conv_appdata(&client),
+            &*client.conversation as *const Tracked<conv::PasswordConv> as *mut c_void
+        );
+    }
+
+    #[test]
+    fn close_session_is_idempotent() {
+        let fixture = teardown_fixture();
+        let infos = Rc::new(RefCell::new(Vec::new()));
+        let mut client = fixture.client(InfoLog(infos.clone()));
+        client.set_item_str(PamItemType::User, "root").unwrap();
+        // Not authenticated yet
+        assert!(client.open_session().is_err());
+        assert!(!client.is_session_open());
+
+        client.authenticate().unwrap();
+        client.open_session().unwrap();
+        assert!(client.is_session_open());
+        assert_eq!(logged_calls(&fixture), "open_session\n");
+        infos.borrow_mut().clear();
+
+        client.close_session().unwrap();
+        client.close_session().unwrap();
+        assert!(!client.is_session_open());
+        drop(client);
+
+        // Closed and deleted the credentials once
+        assert_eq!(logged_calls(&fixture), "open_session\nclose_session\n");
+        assert_eq!(*infos.borrow(), ["You have mail."]);
+    }
+
+    // Accepts everyone, but requires changing the password, which fails until the file
+    // `allow` exists. Logs successful changes with the user to `calls`.
+    const EXPIRED: &str = "\
+auth required pam_permit.so
+account required pam_debug.so acct=new_authtok_reqd
+password requisite pam_exec.so quiet $DIR/check
+password optional pam_exec.so quiet $DIR/log
+password required pam_permit.so
+session required pam_permit.so
+";
+
+    fn expired_fixture() -> Fixture {
+        Fixture::new(EXPIRED)
+            .script("check", "[ -e \"$(dirname \"$0\")/allow\" ]")
+            .script(
+                "log",
+                "echo \"$PAM_TYPE $PAM_USER\" >> \"$(dirname \"$0\")/calls\"",
+            )
+    }
+
+    #[test]
+    fn session_env() {
+        let fixture = Fixture::new(
+            "\
+auth required pam_permit.so
+account required pam_permit.so
+session required pam_env.so conffile=$DIR/env.conf readenv=0 user_readenv=0
+",
+        );
+        std::fs::write(
+            fixture.path().join("env.conf"),
+            "PAM_F_SESSION DEFAULT=opened\n",
+        )
+        .unwrap();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "secret");
+        client.authenticate().unwrap();
+        assert_eq!(client.session_env(), None);
+
+        client.open_session().unwrap();
+        let variable = ("PAM_F_SESSION".to_string(), "opened".to_string());
+        assert!(client.session_env().unwrap().contains(&variable));
+        client.close_session().unwrap();
+        assert_eq!(client.session_env(), None);
+    }
+
+    #[test]
+    fn paste_env() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+        putenv(client.handle, "PAM_F_PASTE=pasted").unwrap();
+        assert!(std::env::var_os("PAM_F_PASTE").is_none());
+
+        client.paste_env();
+        assert_eq!(std::env::var("PAM_F_PASTE").as_deref(), Ok("pasted"));
+    }
+
+    #[test]
+    fn change_expired_authtok_on_same_handle() {
+        let fixture = expired_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "secret");
+        assert_eq!(
+            client.authenticate().map_err(|e| e.0),
+            Err(PamReturnCode::New_Authtok_Reqd)
+        );
+        assert!(client.requires_password_change());
+        assert_eq!(
+            client.open_session().map_err(|e| e.0),
+            Err(PamReturnCode::Perm_Denied)
+        );
+
+        // A failed change keeps the state, a successful one completes the authentication
+        assert!(client
+            .change_authentication_token(PamFlag::Change_Expired_AuthTok)
+            .is_err());
+        assert!(client.requires_password_change());
+        std::fs::write(fixture.path().join("allow"), "").unwrap();
+        client
+            .change_authentication_token(PamFlag::Change_Expired_AuthTok)
+            .unwrap();
+        assert!(!client.requires_password_change());
+        // The user asked for by `authenticate` is still known to the modules
+        assert_eq!(logged_calls(&fixture), "password root\n");
+        client.open_session().unwrap();
+    }
+
+    #[test]
+    fn debug_redacts_credentials() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client
+            .conversation_mut()
+            .set_credentials("debug-user", "hunter2-secret");
+        client
+            .set_item_str(PamItemType::User, "debug-user")
+            .unwrap();
+
+        let debug = format!("{:?}", client);
+        assert!(debug.contains("pam-f-test"), "{}", debug);
+        assert!(debug.contains("\"started\""), "{}", debug);
+        assert!(debug.contains("PasswordConv"), "{}", debug);
+        assert!(!debug.contains("hunter2-secret"), "{}", debug);
+        assert!(!debug.contains("debug-user"), "{}", debug);
+    }
+
+    #[test]
+    fn fast_failures_are_padded() {
+        let min = Duration::from_millis(50);
+        let start = Instant::now();
+        let result: PamResult<()> =
+            with_min_duration(min, || Err(PamError(PamReturnCode::User_Unknown)));
+        assert!(start.elapsed() >= min);
+        assert_eq!(result.map_err(|e| e.0), Err(PamReturnCode::User_Unknown));
+    }
+
+    #[test]
+    fn set_items() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client
+            .set_items(&ItemSet {
+                tty: Some("tty7".to_string()),
+                rhost: Some("example.org".to_string()),
+                ruser: Some("remote".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(item_str(&client, PamItemType::TTY), "tty7");
+        assert_eq!(item_str(&client, PamItemType::RHost), "example.org");
+        assert_eq!(item_str(&client, PamItemType::RUser), "remote");
+
+        let err = client
+            .set_items(&ItemSet {
+                user: Some("user".to_string()),
+                tty: Some("tty\0".to_string()),
+                rhost: Some("example.com".to_string()),
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert_eq!(err.item, PamItemType::TTY);
+        assert_eq!(err.error.0, PamReturnCode::Buf_Err);
+        // Stopped at the failing item
+        assert_eq!(item_str(&client, PamItemType::User), "user");
+        assert_eq!(item_str(&client, PamItemType::RHost), "example.org");
+    }
+
+    // Fails all prompts and counts them
+    #[derive(Default)]
+    struct FailingConv {
+        prompts: usize,
+    }
+
+    impl conv::Conversation for FailingConv {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            self.prompts += 1;
+            Err(())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            self.prompts += 1;
+            Err(())
         }
-        let code = setcred(self.handle, PamFlag::Delete_Cred);
-        end(self.handle, code);
+        fn info(&mut self, _msg: &CStr) {}
+        fn error(&mut self, _msg: &CStr) {}
+    }
+
+    #[test]
+    fn swap_conversation() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(FailingConv::default());
+        // Without a user, modules ask for it via the conversation
+        assert!(client.authenticate().is_err());
+        let first = client.conversation().prompts;
+        assert!(first > 0);
+
+        let old = client.set_conversation(FailingConv::default()).unwrap();
+        assert_eq!(old.prompts, first);
+        assert_eq!(
+            conv_appdata(&client),
+            &*client.conversation as *const Tracked<FailingConv> as *mut c_void
+        );
+        assert!(client.authenticate().is_err());
+        assert_eq!(client.conversation().prompts, first);
+
+        // Switch between different types of handlers
+        let boxed: Box<dyn conv::Conversation> = Box::new(FailingConv::default());
+        let mut client = fixture.client(boxed);
+        client
+            .set_conversation(Box::new(conv::PasswordConv::new()))
+            .unwrap();
+    }
+
+    #[test]
+    fn temporary_conversation() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(FailingConv::default());
+        let own = conv_appdata(&client);
+        let mut first = FailingConv::default();
+        let mut second = FailingConv::default();
+
+        assert!(client.authenticate_with(&mut first).is_err());
+        assert!(first.prompts > 0);
+        assert_eq!(conv_appdata(&client), own);
+        assert!(client
+            .change_authentication_token_with(&mut second, PamFlag::None)
+            .is_err());
+        assert!(second.prompts > 0);
+        assert_eq!(conv_appdata(&client), own);
+        assert_eq!(client.conversation().prompts, 0);
+
+        let prompts = (first.prompts, second.prompts);
+        assert!(client.authenticate().is_err());
+        assert!(client.conversation().prompts > 0);
+        assert_eq!((first.prompts, second.prompts), prompts);
+
+        // Also restored if the operation panics
+        let mut third = FailingConv::default();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            client.with_temporary_conversation(&mut third, |_| panic!("poisoned"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(conv_appdata(&client), own);
+    }
+
+    fn attempts(max_attempts: usize) -> NonZeroUsize {
+        NonZeroUsize::new(max_attempts).unwrap()
+    }
+
+    #[test]
+    fn retry_counts_attempts() {
+        let mut codes = vec![PamReturnCode::Auth_Err, PamReturnCode::Success].into_iter();
+        assert_eq!(
+            retry(attempts(3), || codes.next().unwrap()),
+            (PamReturnCode::Success, 2, false)
+        );
+        assert_eq!(
+            retry(attempts(3), || PamReturnCode::Auth_Err),
+            (PamReturnCode::Auth_Err, 3, true)
+        );
+        // Not worth retrying
+        assert_eq!(
+            retry(attempts(3), || PamReturnCode::Acct_Expired),
+            (PamReturnCode::Acct_Expired, 1, false)
+        );
+
+        // The module giving up is not the same as running out of attempts
+        let mut codes = vec![PamReturnCode::Auth_Err, PamReturnCode::MaxTries].into_iter();
+        assert_eq!(
+            retry(attempts(3), || codes.next().unwrap()),
+            (PamReturnCode::MaxTries, 2, false)
+        );
+    }
+
+    #[test]
+    fn tracked_conversation() {
+        let mut tracked = Tracked::new(conv::PasswordConv::new());
+        tracked.inner.set_credentials("user", "secret");
+        let mut batch = conv::MessageBatchBuilder::new()
+            .push(PamMessageStyle::Text_Info, "Password expires soon")
+            .push(PamMessageStyle::Prompt_Echo_Off, "Password: ")
+            .build();
+
+        batch.converse(&mut tracked).unwrap();
+        assert_eq!(tracked.rounds, 1);
+        assert_eq!(tracked.messages, None);
+
+        tracked.messages = Some(Vec::new());
+        let answers = batch.converse(&mut tracked).unwrap();
+        assert_eq!(answers[1], Some(CString::new("secret").unwrap()));
+        assert_eq!(tracked.rounds, 2);
+        assert_eq!(
+            tracked.messages,
+            Some(vec![(
+                PamMessageStyle::Text_Info,
+                CString::new("Password expires soon").unwrap()
+            )])
+        );
+    }
+
+    #[test]
+    fn authenticate_retry_reports_rounds() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(FailingConv::default());
+        client.set_item_str(PamItemType::User, "root").unwrap();
+        let outcome = client.authenticate_retry(attempts(3));
+        assert_eq!(outcome.code, PamReturnCode::Auth_Err);
+        assert_eq!(outcome.attempts, 3);
+        assert!(outcome.exhausted);
+        // Each attempt asks for the password once, as the handler fails the prompt
+        assert_eq!(client.conversation().prompts, 3);
+        assert!(outcome.rounds >= 3);
+    }
+
+    // Refuses the first attempt, then gives up with PAM_MAXTRIES
+    const MAXTRIES: &str = "\
+auth [success=ignore default=1] pam_exec.so quiet $DIR/again
+auth requisite pam_debug.so auth=maxtries
+auth requisite pam_deny.so
+account required pam_permit.so
+";
+
+    #[test]
+    fn authenticate_retry_stops_on_maxtries() {
+        let fixture = Fixture::new(MAXTRIES).script(
+            "again",
+            "[ -e \"$0.seen\" ] || { touch \"$0.seen\"; exit 1; }",
+        );
+        let mut client = fixture.client(FailingConv::default());
+        client.set_item_str(PamItemType::User, "root").unwrap();
+        let outcome = client.authenticate_retry(attempts(3));
+        assert_eq!(outcome.code, PamReturnCode::MaxTries);
+        assert_eq!(outcome.attempts, 2);
+        assert!(!outcome.exhausted);
+    }
+
+    #[test]
+    fn unset_items() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+        assert!(get_item(client.handle, PamItemType::RHost)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            client.get_user().map_err(|e| e.0),
+            Err(PamReturnCode::User_Unknown)
+        );
+
+        client
+            .set_item_str(PamItemType::RHost, "example.org")
+            .unwrap();
+        clear_item(client.handle, PamItemType::RHost).unwrap();
+        assert!(get_item_cstr(client.handle, PamItemType::RHost)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            get_item_cstr(client.handle, PamItemType::Conv).map_err(|e| e.0),
+            Err(PamReturnCode::Bad_Item)
+        );
+    }
+
+    #[test]
+    fn clear_authtok() {
+        let fixture = token_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "right");
+        client.set_item_str(PamItemType::User, "root").unwrap();
+        client.authenticate().unwrap();
+        assert_eq!(recorded_tokens(&fixture), "right\n");
+        assert_eq!(client.conversation.rounds, 1);
+
+        // Linux-PAM dropped the cached token and the handler forgot its copy, so the module
+        // asks again and gets an empty one
+        client.clear_authtok().unwrap();
+        assert_eq!(
+            client.authenticate().map_err(|e| e.0),
+            Err(PamReturnCode::Auth_Err)
+        );
+        assert_eq!(recorded_tokens(&fixture), "right\n\n");
+        assert_eq!(client.conversation.rounds, 2);
+        assert_eq!(client.get_user().unwrap(), "root");
+    }
+
+    #[test]
+    fn forget_credentials() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "wrong");
+        assert!(client.authenticate().is_err());
+        assert_eq!(client.get_user().unwrap(), "root");
+
+        client.forget_credentials().unwrap();
+        assert_eq!(
+            client.get_user().map_err(|e| e.0),
+            Err(PamReturnCode::User_Unknown)
+        );
+        let prompt = CString::new("login: ").unwrap();
+        let conv: &mut dyn conv::Conversation = client.conversation_mut();
+        assert_eq!(conv.prompt_echo(&prompt), Ok(CString::default()));
+
+        client.conversation_mut().set_credentials("nobody", "right");
+        let rounds = client.conversation.rounds;
+        client.authenticate().unwrap();
+        assert_eq!(client.get_user().unwrap(), "nobody");
+        assert!(client.conversation.rounds > rounds);
+    }
+
+    #[test]
+    fn empty_credentials_are_refused() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().require_non_empty(true);
+        client.conversation_mut().set_credentials("root", "");
+        assert_eq!(
+            client.authenticate().map_err(|e| e.0),
+            Err(PamReturnCode::AuthTok_Recovery_Err)
+        );
+        assert_eq!(client.last_code, PamReturnCode::AuthTok_Recovery_Err);
+    }
+
+    #[test]
+    fn authenticate_with_token() {
+        let fixture = token_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.authenticate_with_token("root", "right").unwrap();
+        assert_eq!(client.get_user().unwrap(), "root");
+        assert_eq!(recorded_tokens(&fixture), "right\n");
+        // Linux-PAM refuses `PAM_AUTHTOK` from applications, so the first module asks for
+        // the token once and caches it, the second one uses it without asking
+        assert_eq!(client.conversation.rounds, 1);
+
+        let mut client = fixture.client(conv::PasswordConv::new());
+        assert_eq!(
+            client
+                .authenticate_with_token("root", "wrong")
+                .map_err(|e| e.0),
+            Err(PamReturnCode::Auth_Err)
+        );
+        assert_eq!(recorded_tokens(&fixture), "right\nwrong\n");
+        assert_eq!(client.conversation.rounds, 1);
+    }
+
+    #[test]
+    fn get_service() {
+        let fixture = Fixture::new(PERMIT);
+        let mut client = fixture.client(conv::PasswordConv::new());
+        assert_eq!(client.get_service().as_deref(), Some("pam-f-test"));
+
+        client
+            .set_item_str(PamItemType::Service, "pam-f-other")
+            .unwrap();
+        assert_eq!(client.get_service().as_deref(), Some("pam-f-other"));
+    }
+
+    #[test]
+    fn remote_items_after_authenticate() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        assert_eq!(client.get_rhost().unwrap(), None);
+        client
+            .set_items(&ItemSet {
+                rhost: Some("example.org".to_string()),
+                ruser: Some("remote".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        client.conversation_mut().set_credentials("root", "wrong");
+        let _ = client.authenticate();
+
+        assert_eq!(client.get_rhost().unwrap().as_deref(), Some("example.org"));
+        assert_eq!(client.get_ruser().unwrap().as_deref(), Some("remote"));
+        assert_eq!(
+            client.get_item_str(PamItemType::User).unwrap().as_deref(),
+            Some("root")
+        );
+        assert_eq!(
+            client.get_item_str(PamItemType::Conv).map_err(|e| e.0),
+            Err(PamReturnCode::Bad_Item)
+        );
+    }
+
+    #[test]
+    fn login_errors() {
+        let classified = LoginError::from_code;
+        assert!(matches!(
+            classified(PamReturnCode::Auth_Err),
+            LoginError::BadCredentials(PamReturnCode::Auth_Err)
+        ));
+        assert!(matches!(
+            classified(PamReturnCode::User_Unknown),
+            LoginError::BadCredentials(_)
+        ));
+        assert!(matches!(
+            classified(PamReturnCode::MaxTries),
+            LoginError::AccountLocked(PamReturnCode::MaxTries)
+        ));
+        assert!(matches!(
+            classified(PamReturnCode::Acct_Expired),
+            LoginError::AccountLocked(_)
+        ));
+        assert!(matches!(
+            classified(PamReturnCode::New_Authtok_Reqd),
+            LoginError::PasswordExpired(_)
+        ));
+        assert!(matches!(
+            classified(PamReturnCode::Authinfo_Unavail),
+            LoginError::SystemError(PamReturnCode::Authinfo_Unavail)
+        ));
+        assert_eq!(
+            classified(PamReturnCode::New_Authtok_Reqd).code(),
+            PamReturnCode::New_Authtok_Reqd
+        );
+        assert_eq!(
+            classified(PamReturnCode::Perm_Denied).to_string(),
+            format!("account locked: {}", PamReturnCode::Perm_Denied)
+        );
+    }
+
+    #[test]
+    fn try_login() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "wrong");
+        let err = client.try_login().unwrap_err();
+        assert!(matches!(err, LoginError::BadCredentials(_)), "{}", err);
+        assert!(client.conversation.messages.is_none());
+
+        // Warnings of the account management are passed on
+        let fixture = Fixture::new(
+            "\
+auth required pam_permit.so
+account optional pam_echo.so Your password will expire in 3 days
+account required pam_permit.so
+session required pam_permit.so
+",
+        );
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "secret");
+        let ok = client.try_login().unwrap();
+        assert_eq!(ok.messages.len(), 1);
+        assert_eq!(ok.expiry(), Some(ExpiryInfo::ExpiresIn { days: 3 }));
+        client.open_session().unwrap();
+
+        let fixture = Fixture::new(
+            "\
+auth required pam_permit.so
+account required pam_debug.so acct=acct_expired
+",
+        );
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "secret");
+        let err = client.try_login().unwrap_err();
+        assert!(
+            matches!(err, LoginError::AccountLocked(PamReturnCode::Acct_Expired)),
+            "{}",
+            err
+        );
+        assert!(!client.requires_password_change());
+    }
+
+    #[test]
+    fn resume_login() {
+        let fixture = expired_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        client.conversation_mut().set_credentials("root", "secret");
+        let change = match client.try_login() {
+            Err(LoginError::PasswordExpired(change)) => change,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert!(client.requires_password_change());
+
+        std::fs::write(fixture.path().join("allow"), "").unwrap();
+        client.resume_login(change).unwrap();
+        assert!(!client.requires_password_change());
+        assert_eq!(logged_calls(&fixture), "password root\n");
+        client.open_session().unwrap();
+    }
+
+    #[cfg(feature = "syslog")]
+    #[test]
+    fn audit_log() {
+        let fixture = password_fixture();
+        let mut client = fixture.client(conv::PasswordConv::new());
+        let audit_log = crate::AuditLog::new()
+            .facility(libc::LOG_AUTH)
+            .ident("pam-f-test")
+            .unwrap();
+        client.set_audit_log(Some(audit_log));
+        client.conversation_mut().set_credentials("root", "wrong");
+        assert_eq!(
+            client.authenticate().map_err(|e| e.0),
+            Err(client.last_code)
+        );
     }
 }
@@ -1,8 +1,12 @@
-use libc::{c_int, c_void, calloc, free, size_t, strdup};
+use libc::{c_int, c_void};
 
 use std::ffi::{CStr, CString};
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
 
+use crate::alloc::{alloc_c, dup_bytes, dup_c, free_c};
 use crate::{ffi::pam_conv, PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
 
 /// A trait representing the PAM authentification conversation
@@ -32,15 +36,275 @@ pub trait Conversation {
     fn info(&mut self, msg: &CStr);
     /// This is an error message from PAM
     fn error(&mut self, msg: &CStr);
+    /// PAM requests the answer to a radio button style question (Linux-PAM extension)
+    ///
+    /// The default implementation rejects the prompt.
+    #[allow(clippy::result_unit_err)]
+    fn prompt_radio(&mut self, _msg: &CStr) -> Result<CString, ()> {
+        Err(())
+    }
+    /// PAM sends a binary prompt (Linux-PAM extension)
+    ///
+    /// `data` is the complete binary packet, including its header. The returned packet is
+    /// passed back to the module as is, so it has to be complete as well (see
+    /// `BinaryPacket`), otherwise the conversation fails with `PAM_CONV_ERR`.
+    ///
+    /// The default implementation rejects the prompt.
+    #[allow(clippy::result_unit_err)]
+    fn binary_prompt(&mut self, _data: &[u8]) -> Result<Vec<u8>, ()> {
+        Err(())
+    }
+    /// PAM is about to pass a batch of `num_msg` messages
+    ///
+    /// This is called once each time PAM invokes the conversation, before any of the
+    /// messages are dispatched. The default implementation does nothing, but it can be
+    /// used to record metrics such as the number of conversation rounds and messages.
+    fn begin_batch(&mut self, _num_msg: usize) {}
+    /// The message styles this handler can service
+    ///
+    /// Messages of any other style are rejected before they reach the handler: its `error`
+    /// method is called with an explanation and the conversation fails with `PAM_CONV_ERR`.
+    /// By default, these are the four classic styles, i.e. neither `Radio_Type` nor
+    /// `Binary_Prompt`. Handlers implementing `prompt_radio` or `binary_prompt` have to
+    /// override this method.
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        &CLASSIC_STYLES
+    }
+    /// Forget any authentication token (e.g. password) this handler stores
+    ///
+    /// Called by `Client::clear_authtok`. The default implementation does nothing.
+    fn forget_authtok(&mut self) {}
+    /// Forget all credentials (e.g. login and password) this handler stores
+    ///
+    /// Called by `Client::forget_credentials`. The default implementation calls
+    /// `forget_authtok`.
+    fn forget_credentials(&mut self) {
+        self.forget_authtok()
+    }
+    /// Answer a whole batch of messages at once
+    ///
+    /// This is called once each time PAM invokes the conversation, with all messages of
+    /// that round (after `begin_batch`). Overriding it allows handlers to see every
+    /// message before answering, e.g. to present all prompts in a single dialog. It has
+    /// to return exactly one entry per message: `None` for messages without an answer
+    /// (`Text_Info` and `Error_Msg`) and the answer otherwise. Returning an error aborts
+    /// the conversation with the given code.
+    ///
+    /// The default implementation dispatches each message to the respective method of
+    /// this trait and fails with `PAM_CONV_ERR` as soon as one of them fails.
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        messages.iter().map(|m| dispatch(self, m)).collect()
+    }
+}
+
+/// Forwards to the boxed handler, which allows using `Box<dyn Conversation>` to switch
+/// between different types of handlers
+impl<C: Conversation + ?Sized> Conversation for Box<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        (**self).prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        (**self).prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        (**self).info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        (**self).error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        (**self).prompt_radio(msg)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        (**self).binary_prompt(data)
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        (**self).begin_batch(num_msg)
+    }
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        (**self).supported_styles()
+    }
+    fn forget_authtok(&mut self) {
+        (**self).forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        (**self).forget_credentials()
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        (**self).converse_batch(messages)
+    }
+}
+
+const CLASSIC_STYLES: [PamMessageStyle; 4] = [
+    PamMessageStyle::Prompt_Echo_On,
+    PamMessageStyle::Prompt_Echo_Off,
+    PamMessageStyle::Error_Msg,
+    PamMessageStyle::Text_Info,
+];
+
+#[cfg(target_os = "linux")]
+const ALL_STYLES: [PamMessageStyle; 6] = [
+    PamMessageStyle::Prompt_Echo_On,
+    PamMessageStyle::Prompt_Echo_Off,
+    PamMessageStyle::Error_Msg,
+    PamMessageStyle::Text_Info,
+    PamMessageStyle::Radio_Type,
+    PamMessageStyle::Binary_Prompt,
+];
+#[cfg(not(target_os = "linux"))]
+const ALL_STYLES: [PamMessageStyle; 4] = CLASSIC_STYLES;
+
+/// The content of a message passed to the conversation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MessageContent<'a> {
+    /// The text of any message which is not a binary prompt
+    Text(&'a CStr),
+    /// A binary prompt, including its header
+    Binary(&'a [u8]),
+}
+
+/// A borrowed view on a Linux-PAM binary packet
+///
+/// On the wire, a packet starts with its total length (4 bytes, big endian, including the
+/// header), followed by a type byte and the payload. Binary prompts and their responses
+/// both use this format.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BinaryPacket<'a> {
+    /// The type of the packet, its meaning is up to the module
+    pub kind: u8,
+    /// The payload following the header
+    pub payload: &'a [u8],
+}
+
+impl<'a> BinaryPacket<'a> {
+    /// The length of the header, i.e. of the length and the type
+    pub const HEADER_LEN: usize = 5;
+
+    /// Create a packet of type `kind` with `payload`
+    pub fn new(kind: u8, payload: &'a [u8]) -> BinaryPacket<'a> {
+        BinaryPacket { kind, payload }
+    }
+
+    /// Parse `packet`, which has to consist of exactly one binary packet
+    ///
+    /// Returns `None` if `packet` is shorter than the header or its length does not match
+    /// the one stated in the header.
+    pub fn decode(packet: &'a [u8]) -> Option<BinaryPacket<'a>> {
+        if packet.len() < Self::HEADER_LEN {
+            return None;
+        }
+        if Self::stated_len(packet) != Some(packet.len()) {
+            return None;
+        }
+        Some(BinaryPacket {
+            kind: packet[4],
+            payload: &packet[Self::HEADER_LEN..],
+        })
+    }
+
+    /// Serialize the packet, including its header
+    ///
+    /// # Panics
+    ///
+    /// If the packet is too long for its length to fit into the header.
+    pub fn encode(&self) -> Vec<u8> {
+        let len =
+            u32::try_from(self.payload.len() + Self::HEADER_LEN).expect("binary packet too long");
+        let mut packet = Vec::with_capacity(len as usize);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.push(self.kind);
+        packet.extend_from_slice(self.payload);
+        packet
+    }
+
+    // The total length stated in the header, `None` if it is shorter than the header itself
+    fn stated_len(header: &[u8]) -> Option<usize> {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len < Self::HEADER_LEN {
+            None
+        } else {
+            Some(len)
+        }
+    }
+}
+
+/// A borrowed view on a single message PAM passes to the conversation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RawMessage<'a> {
+    /// The style of the message
+    pub style: PamMessageStyle,
+    /// The content of the message
+    pub content: MessageContent<'a>,
+}
+
+impl<'a> RawMessage<'a> {
+    /// Create a view on the given message
+    ///
+    /// Returns `None` if the message is malformed, i.e. it has an unknown style, no content
+    /// or a binary prompt claims to be shorter than its header.
+    ///
+    /// # Safety
+    ///
+    /// `msg.msg` has to be null or point to a nul-terminated string (or, for binary
+    /// prompts, to a packet of the length stated in its header) which is valid for `'a`.
+    pub unsafe fn from_pam(msg: &'a PamMessage) -> Option<RawMessage<'a>> {
+        if msg.msg.is_null() {
+            return None;
+        }
+
+        let style = PamMessageStyle::from(msg.msg_style);
+        if style as c_int != msg.msg_style {
+            return None;
+        }
+        let content = match style {
+            #[cfg(target_os = "linux")]
+            PamMessageStyle::Binary_Prompt => {
+                let header = std::slice::from_raw_parts(msg.msg as *const u8, 4);
+                let len = BinaryPacket::stated_len(header)?;
+                MessageContent::Binary(std::slice::from_raw_parts(msg.msg as *const u8, len))
+            }
+            _ => MessageContent::Text(CStr::from_ptr(msg.msg)),
+        };
+        Some(RawMessage { style, content })
+    }
+}
+
+/// The answer to a single message, see `Conversation::converse_batch`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The answer to a text prompt
+    Text(CString),
+    /// The answer to a binary prompt, i.e. a complete binary packet
+    Binary(Vec<u8>),
 }
 
 /// A minimalistic conversation handler, that uses given login and password
 ///
 /// This conversation handler is not really interactive, but simply returns to
 /// PAM the value that have been set using the `set_credentials` method.
+///
+/// Modules asking further questions in the same batch (e.g. for a PIN after the password)
+/// can be answered with `set_extra_blind_answers` and `set_extra_echo_answer`.
 pub struct PasswordConv {
     login: String,
     passwd: String,
+    // Takes precedence over `passwd` if set
+    #[cfg(feature = "secrecy")]
+    secret: Option<secrecy::SecretString>,
+    non_empty: bool,
+    // Whether a prompt of the current batch was refused for an empty answer
+    refused_empty: bool,
+    extra_blind: Vec<String>,
+    extra_echo: Option<String>,
+    // Prompts of each kind answered in the current batch
+    blind_answered: usize,
+    echo_answered: usize,
 }
 
 impl PasswordConv {
@@ -49,95 +313,2449 @@ impl PasswordConv {
         PasswordConv {
             login: String::new(),
             passwd: String::new(),
+            #[cfg(feature = "secrecy")]
+            secret: None,
+            non_empty: false,
+            refused_empty: false,
+            extra_blind: Vec::new(),
+            extra_echo: None,
+            blind_answered: 0,
+            echo_answered: 0,
         }
     }
 
     /// Set the credentials that this handler will provide to PAM
     pub fn set_credentials<U: Into<String>, V: Into<String>>(&mut self, login: U, password: V) {
+        self.wipe();
         self.login = login.into();
         self.passwd = password.into();
     }
+
+    /// Set the password this handler will provide to PAM from a `SecretString`
+    ///
+    /// Replaces any password set before, the login is kept. The password is only exposed
+    /// for answering a prompt; the copy passed to PAM is wiped right after PAM got its own
+    /// copy. Requires the `secrecy` feature.
+    #[cfg(feature = "secrecy")]
+    pub fn set_password_secret(&mut self, password: secrecy::SecretString) {
+        zeroize::Zeroize::zeroize(&mut self.passwd);
+        self.secret = Some(password);
+    }
+
+    /// Create a new handler with a copy of the credentials of this one
+    ///
+    /// This is deliberately not `Clone`, to avoid accidental copies of credentials (e.g. in
+    /// generic code). Keep in mind that every duplicate holds another copy of the password
+    /// in memory. With the `zeroize` feature enabled, each copy is wiped on drop.
+    pub fn duplicate(&self) -> PasswordConv {
+        PasswordConv {
+            login: self.login.clone(),
+            passwd: self.passwd.clone(),
+            #[cfg(feature = "secrecy")]
+            secret: self.secret.clone(),
+            non_empty: self.non_empty,
+            refused_empty: false,
+            extra_blind: self.extra_blind.clone(),
+            extra_echo: self.extra_echo.clone(),
+            blind_answered: 0,
+            echo_answered: 0,
+        }
+    }
+
+    /// Answer further `Prompt_Echo_Off` prompts in a batch with `answers`, e.g. with a PIN
+    ///
+    /// Within each batch of messages, the first blind prompt is answered with the password,
+    /// the second one with the first of `answers`, and so on. Once `answers` are used up,
+    /// the password is given again. As `PasswordConv` cannot tell prompts apart, this only
+    /// helps with modules asking all their questions in one batch: a module asking in
+    /// separate conversation rounds always gets the password. Replaces any answers set
+    /// before.
+    pub fn set_extra_blind_answers<I, S>(&mut self, answers: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.wipe_extra_blind();
+        self.extra_blind = answers.into_iter().map(Into::into).collect();
+    }
+
+    /// Answer further `Prompt_Echo_On` prompts in a batch with `answer`
+    ///
+    /// Within each batch of messages, the first echo prompt is answered with the login and
+    /// all further ones with `answer`, or again with the login if it is `None` (the
+    /// default).
+    pub fn set_extra_echo_answer<S: Into<String>>(&mut self, answer: Option<S>) {
+        self.extra_echo = answer.map(Into::into);
+    }
+
+    /// Refuse to answer prompts with an empty login or password
+    ///
+    /// Some modules treat empty credentials inconsistently (e.g. as anonymous login). When
+    /// enabled, prompts for an empty credential fail right away, so the empty value never
+    /// reaches a module. The conversation then fails with `PAM_AUTHTOK_RECOVERY_ERR`
+    /// instead of `PAM_CONV_ERR`, which modules asking for the password usually pass on
+    /// (a failed login prompt is always reported as `PAM_CONV_ERR` by `pam_get_user`,
+    /// though). Disabled by default.
+    pub fn require_non_empty(&mut self, require: bool) {
+        self.non_empty = require;
+    }
+
+    // Answer with the value picked by `value`, unless it is empty and refused
+    fn answer(&mut self, value: impl FnOnce(&PasswordConv) -> &str) -> Result<CString, ()> {
+        let value = value(self);
+        let refused = self.non_empty && value.is_empty();
+        let answer = CString::new(value).map_err(|_| ());
+        if refused {
+            self.refused_empty = true;
+            return Err(());
+        }
+        answer
+    }
+
+    fn password(&self) -> &str {
+        #[cfg(feature = "secrecy")]
+        if let Some(secret) = &self.secret {
+            return secrecy::ExposeSecret::expose_secret(secret);
+        }
+        &self.passwd
+    }
+
+    fn wipe_extra_blind(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut self.extra_blind);
+        self.extra_blind.clear();
+    }
+
+    fn wipe(&mut self) {
+        #[cfg(feature = "zeroize")]
+        {
+            use zeroize::Zeroize;
+            self.login.zeroize();
+            self.passwd.zeroize();
+        }
+        self.wipe_extra_blind();
+        // Wipes itself on drop
+        #[cfg(feature = "secrecy")]
+        {
+            self.secret = None;
+        }
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for PasswordConv {
+    fn drop(&mut self) {
+        self.wipe();
+    }
 }
 
 impl Conversation for PasswordConv {
     fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
-        CString::new(self.login.clone()).map_err(|_| ())
+        self.echo_answered += 1;
+        self.answer(|conv| match &conv.extra_echo {
+            Some(answer) if conv.echo_answered > 1 => answer,
+            _ => &conv.login,
+        })
     }
     fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
-        CString::new(self.passwd.clone()).map_err(|_| ())
+        self.blind_answered += 1;
+        self.answer(|conv| {
+            match conv
+                .blind_answered
+                .checked_sub(2)
+                .and_then(|i| conv.extra_blind.get(i))
+            {
+                Some(answer) => answer,
+                None => conv.password(),
+            }
+        })
+    }
+    fn begin_batch(&mut self, _num_msg: usize) {
+        self.blind_answered = 0;
+        self.echo_answered = 0;
+        self.refused_empty = false;
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        messages
+            .iter()
+            .map(|m| match dispatch(self, m) {
+                Err(_) if self.refused_empty => Err(PamReturnCode::AuthTok_Recovery_Err),
+                result => result,
+            })
+            .collect()
     }
     fn info(&mut self, _msg: &CStr) {}
     fn error(&mut self, msg: &CStr) {
         eprintln!("[PAM ERROR] {}", msg.to_string_lossy());
     }
+    fn forget_authtok(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut self.passwd);
+        self.passwd.clear();
+        #[cfg(feature = "secrecy")]
+        {
+            self.secret = None;
+        }
+        self.wipe_extra_blind();
+    }
+    fn forget_credentials(&mut self) {
+        self.wipe();
+        self.login.clear();
+        self.passwd.clear();
+    }
 }
 
-pub(crate) fn into_pam_conv<C: Conversation>(conv: &mut C) -> pam_conv {
-    pam_conv {
-        conv: Some(converse::<C>),
-        appdata_ptr: conv as *mut C as *mut c_void,
+/// A non-interactive conversation handler, that fails every prompt
+///
+/// This is meant for the stages after authentication (e.g. `acct_mgmt` and
+/// `open_session`), where a module asking for input indicates a misconfiguration. Every
+/// prompt fails right away, which PAM reports as `PAM_CONV_ERR`, instead of blocking.
+/// Informational and error messages are silently discarded (an error message still fails
+/// the conversation, like with any other handler).
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NullConv;
+
+impl Conversation for NullConv {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+        Err(())
+    }
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+        Err(())
     }
+    fn info(&mut self, _msg: &CStr) {}
+    fn error(&mut self, _msg: &CStr) {}
 }
 
-// FIXME: verify this
-pub(crate) unsafe extern "C" fn converse<C: Conversation>(
-    num_msg: c_int,
-    msg: *mut *const PamMessage,
-    out_resp: *mut *mut PamResponse,
-    appdata_ptr: *mut c_void,
-) -> c_int {
-    // allocate space for responses
-    let resp =
-        calloc(num_msg as usize, mem::size_of::<PamResponse>() as size_t) as *mut PamResponse;
-    if resp.is_null() {
-        return PamReturnCode::Buf_Err as c_int;
+/// A source of passwords for `KeyringConv`, e.g. a credential cache or a vault
+pub trait SecretStore {
+    /// The answer to the blind prompt `prompt` for `user`, `None` if there is none
+    fn get(&mut self, user: &str, prompt: &CStr) -> Option<CString>;
+}
+
+/// A non-interactive conversation handler answering blind prompts from a `SecretStore`
+///
+/// Conversation handlers have no access to the PAM handle, so the user to look up has to
+/// be set with `set_user` (usually the same user set as the `PAM_USER` item). Prompts for
+/// the user are answered with it. Blind prompts are answered by the store, a miss (or no
+/// user being set) fails the prompt. Informational and error messages are discarded.
+pub struct KeyringConv<S> {
+    store: S,
+    user: Option<String>,
+}
+
+impl<S: SecretStore> KeyringConv<S> {
+    /// Create a handler looking up passwords in `store`, without a user
+    pub fn new(store: S) -> KeyringConv<S> {
+        KeyringConv { store, user: None }
     }
 
-    let handler = &mut *(appdata_ptr as *mut C);
+    /// Set the user to look up passwords for, or none
+    pub fn set_user<U: Into<String>>(&mut self, user: Option<U>) {
+        self.user = user.map(Into::into);
+    }
 
-    let mut result: PamReturnCode = PamReturnCode::Success;
-    for i in 0..num_msg as isize {
-        // get indexed values
-        // FIXME: check this
-        let m: &mut PamMessage = &mut *(*(msg.offset(i)) as *mut PamMessage);
-        let r: &mut PamResponse = &mut *(resp.offset(i));
-
-        let msg = CStr::from_ptr(m.msg);
-        // match on msg_style
-        match PamMessageStyle::from(m.msg_style) {
-            PamMessageStyle::Prompt_Echo_On => {
-                if let Ok(handler_response) = handler.prompt_echo(msg) {
-                    r.resp = strdup(handler_response.as_ptr());
-                } else {
-                    result = PamReturnCode::Conv_Err;
-                }
-            }
-            PamMessageStyle::Prompt_Echo_Off => {
-                if let Ok(handler_response) = handler.prompt_blind(msg) {
-                    r.resp = strdup(handler_response.as_ptr());
-                } else {
-                    result = PamReturnCode::Conv_Err;
-                }
-            }
-            PamMessageStyle::Text_Info => {
-                handler.info(msg);
-            }
-            PamMessageStyle::Error_Msg => {
-                handler.error(msg);
-                result = PamReturnCode::Conv_Err;
+    /// The user passwords are looked up for
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Immutable access to the store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Mutable access to the store
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Unwrap the store
+    pub fn into_store(self) -> S {
+        self.store
+    }
+}
+
+impl<S: SecretStore> Conversation for KeyringConv<S> {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+        let user = self.user.as_deref().ok_or(())?;
+        CString::new(user).map_err(|_| ())
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        let user = self.user.as_deref().ok_or(())?;
+        self.store.get(user, msg).ok_or(())
+    }
+    fn info(&mut self, _msg: &CStr) {}
+    fn error(&mut self, _msg: &CStr) {}
+    fn forget_credentials(&mut self) {
+        self.user = None;
+    }
+}
+
+/// A message sent to the UI by `ChannelConv`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelMessage {
+    /// A prompt (`Prompt_Echo_On` or `Prompt_Echo_Off`), which has to be answered
+    ///
+    /// The answer has to be sent together with `id`, which is unique for each prompt of
+    /// a `ChannelConv`.
+    Prompt {
+        id: u64,
+        style: PamMessageStyle,
+        text: CString,
+    },
+    /// The prompt with the given `id` timed out and must no longer be answered, see
+    /// `ChannelConv::set_timeout`
+    Cancelled { id: u64 },
+    /// An informational message, which expects no answer
+    Info { text: CString },
+    /// An error message, which expects no answer
+    Error { text: CString },
+    /// Sent while waiting for the answer to a prompt, see `ChannelConv::set_keepalive`
+    Keepalive,
+}
+
+impl ChannelMessage {
+    /// Whether the UI has to send back an answer, i.e. this is a `Prompt`
+    pub fn expects_answer(&self) -> bool {
+        matches!(self, ChannelMessage::Prompt { .. })
+    }
+}
+
+/// A conversation handler that forwards every message over a channel
+///
+/// This is meant for UIs (e.g. greeters) which answer prompts from a different thread
+/// than the one driving PAM. Every message is sent to the UI as a `ChannelMessage` and
+/// for a `ChannelMessage::Prompt` the handler then blocks until the UI sends back the
+/// answer, together with the `id` of the prompt.
+///
+/// Neither side can hang forever if the other one goes away:
+/// - Dropping the `ChannelConv` (or the `Client` owning it) closes the prompt channel, so
+///   a `recv` on the UI side returns an error.
+/// - Dropping the answer `Sender` on the UI side makes a blocked prompt return `Err(())`,
+///   which is reported to PAM as `PAM_CONV_ERR` and aborts the transaction.
+/// - If a timeout is set with `set_timeout` and no answer arrives in time, the prompt
+///   fails the same way and a `ChannelMessage::Cancelled` is sent. An answer arriving
+///   after that is discarded, as answers to any other than the current prompt are, so it
+///   is never taken as the answer to a later prompt.
+/// - If a keepalive interval is set with `set_keepalive`, a `ChannelMessage::Keepalive`
+///   is sent whenever no answer arrived within it, which also notices a UI gone away in
+///   between (e.g. when the messages are forwarded over the network).
+pub struct ChannelConv {
+    prompts: Sender<ChannelMessage>,
+    answers: Receiver<(u64, CString)>,
+    timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    // The id of the next prompt
+    next_id: u64,
+}
+
+impl ChannelConv {
+    /// Create a new `ChannelConv` handler
+    ///
+    /// Returns the handler itself, the receiver for messages from PAM and the sender for
+    /// the answers to prompts, each with the `id` of its prompt. The latter two are meant
+    /// to be moved into the UI thread.
+    pub fn new() -> (
+        ChannelConv,
+        Receiver<ChannelMessage>,
+        Sender<(u64, CString)>,
+    ) {
+        let (prompt_tx, prompt_rx) = channel();
+        let (answer_tx, answer_rx) = channel();
+        let conv = ChannelConv {
+            prompts: prompt_tx,
+            answers: answer_rx,
+            timeout: None,
+            keepalive: None,
+            next_id: 0,
+        };
+        (conv, prompt_rx, answer_tx)
+    }
+
+    /// Limit the time to wait for the answer to each prompt
+    ///
+    /// `None` (the default) waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// The time to wait for the answer to each prompt
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Send a `ChannelMessage::Keepalive` every `interval` while waiting for an answer
+    ///
+    /// `None` (the default) sends none. The UI must not answer keepalives. If sending one
+    /// fails because the UI is gone, the prompt fails right away.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+    }
+
+    /// The interval of keepalives while waiting for an answer
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    fn ask(&mut self, style: PamMessageStyle, msg: &CStr) -> Result<CString, ()> {
+        let text = msg.to_owned();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.prompts
+            .send(ChannelMessage::Prompt { id, style, text })
+            .map_err(|_| ())?;
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        loop {
+            let wake = match (deadline, self.keepalive) {
+                (None, None) => None,
+                (Some(deadline), None) => Some(deadline),
+                (None, Some(interval)) => Some(Instant::now() + interval),
+                (Some(deadline), Some(interval)) => Some(deadline.min(Instant::now() + interval)),
+            };
+            let answer = match wake {
+                None => self
+                    .answers
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+                Some(wake) => self
+                    .answers
+                    .recv_timeout(wake.saturating_duration_since(Instant::now())),
+            };
+            match answer {
+                Ok((answer_id, answer)) if answer_id == id => return Ok(answer),
+                // Late answer to a previous prompt
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => match deadline {
+                    Some(deadline) if Instant::now() >= deadline => {
+                        let _ = self.prompts.send(ChannelMessage::Cancelled { id });
+                        return Err(());
+                    }
+                    // Woken up for the keepalive
+                    _ => self
+                        .prompts
+                        .send(ChannelMessage::Keepalive)
+                        .map_err(|_| ())?,
+                },
+                Err(RecvTimeoutError::Disconnected) => return Err(()),
             }
         }
-        if result != PamReturnCode::Success {
-            break;
+    }
+}
+
+impl Conversation for ChannelConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.ask(PamMessageStyle::Prompt_Echo_On, msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.ask(PamMessageStyle::Prompt_Echo_Off, msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        // Nobody listening is not an error for messages which need no answer
+        let text = msg.to_owned();
+        let _ = self.prompts.send(ChannelMessage::Info { text });
+    }
+    fn error(&mut self, msg: &CStr) {
+        let text = msg.to_owned();
+        let _ = self.prompts.send(ChannelMessage::Error { text });
+    }
+}
+
+/// A conversation handler limiting the size of the responses of another handler
+///
+/// Responses longer than the limit (in bytes, excluding the nul terminator) are rejected
+/// with `Err(())`, which is reported to PAM as `PAM_CONV_ERR`. This protects against
+/// hostile or buggy handlers, e.g. when answers are received over the network.
+pub struct LimitedConv<C> {
+    inner: C,
+    limit: usize,
+}
+
+impl<C: Conversation> LimitedConv<C> {
+    /// The default limit of 4 KiB
+    pub const DEFAULT_LIMIT: usize = 4096;
+
+    /// Wrap `inner`, limiting its responses to `DEFAULT_LIMIT` bytes
+    pub fn new(inner: C) -> LimitedConv<C> {
+        LimitedConv::with_limit(inner, Self::DEFAULT_LIMIT)
+    }
+
+    /// Wrap `inner`, limiting its responses to `limit` bytes
+    pub fn with_limit(inner: C, limit: usize) -> LimitedConv<C> {
+        LimitedConv { inner, limit }
+    }
+
+    /// Immutable access to the wrapped handler
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Mutable access to the wrapped handler
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn check(&self, len: usize) -> Result<(), ()> {
+        if len > self.limit {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<C: Conversation> Conversation for LimitedConv<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        let response = self.inner.prompt_echo(msg)?;
+        self.check(response.as_bytes().len())?;
+        Ok(response)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        let response = self.inner.prompt_blind(msg)?;
+        self.check(response.as_bytes().len())?;
+        Ok(response)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        let response = self.inner.prompt_radio(msg)?;
+        self.check(response.as_bytes().len())?;
+        Ok(response)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let response = self.inner.binary_prompt(data)?;
+        self.check(response.len())?;
+        Ok(response)
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        self.inner.begin_batch(num_msg)
+    }
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        self.inner.supported_styles()
+    }
+    fn forget_authtok(&mut self) {
+        self.inner.forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        self.inner.forget_credentials()
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        let responses = self.inner.converse_batch(messages)?;
+        for response in responses.iter().flatten() {
+            let len = match response {
+                Response::Text(text) => text.as_bytes().len(),
+                Response::Binary(data) => data.len(),
+            };
+            self.check(len).map_err(|_| PamReturnCode::Conv_Err)?;
+        }
+        Ok(responses)
+    }
+}
+
+/// A conversation handler answering binary prompts with a closure
+///
+/// Binary prompts (a Linux-PAM extension, used e.g. by hardware token modules to exchange
+/// challenges and responses) are passed to `answer`, all other messages to `inner`.
+/// `answer` gets the complete packet and has to return a complete packet, i.e. starting
+/// with its total length (4 bytes, big endian) and a type byte, see `BinaryPacket`.
+/// Packets whose length does not match their header are rejected with `PAM_CONV_ERR`, as
+/// the module would read past their end otherwise.
+#[cfg(target_os = "linux")]
+pub struct BinaryConv<C, F> {
+    inner: C,
+    answer: F,
+    styles: Vec<PamMessageStyle>,
+}
+
+#[cfg(target_os = "linux")]
+impl<C, F> BinaryConv<C, F>
+where
+    C: Conversation,
+    F: FnMut(&[u8]) -> Result<Vec<u8>, ()>,
+{
+    /// Wrap `inner`, answering binary prompts with `answer`
+    ///
+    /// Supports the message styles `inner` supports at this point, plus `Binary_Prompt`.
+    pub fn new(inner: C, answer: F) -> BinaryConv<C, F> {
+        let mut styles = inner.supported_styles().to_vec();
+        if !styles.contains(&PamMessageStyle::Binary_Prompt) {
+            styles.push(PamMessageStyle::Binary_Prompt);
+        }
+        BinaryConv {
+            inner,
+            answer,
+            styles,
+        }
+    }
+
+    /// Immutable access to the wrapped handler
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Mutable access to the wrapped handler
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<C, F> Conversation for BinaryConv<C, F>
+where
+    C: Conversation,
+    F: FnMut(&[u8]) -> Result<Vec<u8>, ()>,
+{
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_radio(msg)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let packet = (self.answer)(data)?;
+        match BinaryPacket::decode(&packet) {
+            Some(_) => Ok(packet),
+            None => Err(()),
+        }
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        self.inner.begin_batch(num_msg)
+    }
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        &self.styles
+    }
+    fn forget_authtok(&mut self) {
+        self.inner.forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        self.inner.forget_credentials()
+    }
+    // The other messages are passed on to `inner` as one batch, before the binary
+    // prompts are answered
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        let rest: Vec<_> = messages
+            .iter()
+            .filter(|m| m.style != PamMessageStyle::Binary_Prompt)
+            .copied()
+            .collect();
+        let mut answered = Wiped(if rest.is_empty() {
+            Vec::new()
+        } else {
+            self.inner.converse_batch(&rest)?
+        });
+        if answered.0.len() != rest.len() {
+            return Err(PamReturnCode::Conv_Err);
+        }
+
+        let mut answers = answered.0.iter_mut().map(Option::take);
+        let mut responses = Wiped(Vec::with_capacity(messages.len()));
+        for m in messages {
+            let response = match m.style {
+                PamMessageStyle::Binary_Prompt => dispatch(self, m)?,
+                _ => answers.next().flatten(),
+            };
+            responses.0.push(response);
+        }
+        Ok(mem::take(&mut responses.0))
+    }
+}
+
+/// A wrapper rejecting messages of styles which are not explicitly allowed
+///
+/// If a round contains a message of any other style, the whole round fails with the
+/// configured return code (e.g. `PAM_PERM_DENIED`) before any message reaches the wrapped
+/// handler. This allows enforcing a policy like "no binary prompts" independent of the
+/// styles the wrapped handler supports. Allowed messages of styles the wrapped handler
+/// does not support are still rejected with `PAM_CONV_ERR`, see
+/// `Conversation::supported_styles`.
+pub struct StyleFilterConv<C> {
+    inner: C,
+    allowed: Vec<PamMessageStyle>,
+    rejection: PamReturnCode,
+}
+
+impl<C: Conversation> StyleFilterConv<C> {
+    /// Wrap `inner`, failing rounds containing styles not in `allowed` with `rejection`
+    pub fn new(
+        inner: C,
+        allowed: &[PamMessageStyle],
+        rejection: PamReturnCode,
+    ) -> StyleFilterConv<C> {
+        StyleFilterConv {
+            inner,
+            allowed: allowed.to_vec(),
+            rejection,
         }
     }
 
-    // free allocated memory if an error occured
-    if result != PamReturnCode::Success {
-        free(resp as *mut c_void);
-    } else {
-        *out_resp = resp;
+    /// Immutable access to the wrapped handler
+    pub fn inner(&self) -> &C {
+        &self.inner
     }
 
-    result as c_int
+    /// Mutable access to the wrapped handler
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Conversation> Conversation for StyleFilterConv<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_radio(msg)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.inner.binary_prompt(data)
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        self.inner.begin_batch(num_msg)
+    }
+    // Every style has to reach `converse_batch` to be filtered there
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        &ALL_STYLES
+    }
+    fn forget_authtok(&mut self) {
+        self.inner.forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        self.inner.forget_credentials()
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        if messages.iter().any(|m| !self.allowed.contains(&m.style)) {
+            return Err(self.rejection);
+        }
+        check_styles(&mut self.inner, messages)?;
+        self.inner.converse_batch(messages)
+    }
+}
+
+/// A wrapper measuring how long the wrapped handler takes to answer each prompt
+///
+/// The wall-clock duration of each `prompt_echo` and `prompt_blind` call is recorded
+/// together with the style of the prompt, e.g. to find slow backends of multi-factor
+/// authentication or to choose timeouts. Everything else is passed through untouched.
+///
+/// Batches are passed on to the wrapped handler as a whole, which may answer all of their
+/// prompts at once (see `Conversation::converse_batch`). So prompts of the same batch are
+/// all recorded with the duration of the whole batch.
+pub struct TimingConv<C> {
+    inner: C,
+    timings: Vec<(PamMessageStyle, Duration)>,
+}
+
+impl<C: Conversation> TimingConv<C> {
+    /// Wrap `inner`, timing its answers to prompts
+    pub fn new(inner: C) -> TimingConv<C> {
+        TimingConv {
+            inner,
+            timings: Vec::new(),
+        }
+    }
+
+    /// The style and duration of every prompt answered so far, in order
+    pub fn timings(&self) -> Vec<(PamMessageStyle, Duration)> {
+        self.timings.clone()
+    }
+
+    /// Forget the timings recorded so far
+    pub fn clear_timings(&mut self) {
+        self.timings.clear();
+    }
+
+    /// Immutable access to the wrapped handler
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Mutable access to the wrapped handler
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn timed<T>(&mut self, styles: &[PamMessageStyle], prompt: impl FnOnce(&mut C) -> T) -> T {
+        let start = Instant::now();
+        let result = prompt(&mut self.inner);
+        let elapsed = start.elapsed();
+        self.timings
+            .extend(styles.iter().map(|style| (*style, elapsed)));
+        result
+    }
+}
+
+impl<C: Conversation> Conversation for TimingConv<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.timed(&[PamMessageStyle::Prompt_Echo_On], |inner| {
+            inner.prompt_echo(msg)
+        })
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.timed(&[PamMessageStyle::Prompt_Echo_Off], |inner| {
+            inner.prompt_blind(msg)
+        })
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_radio(msg)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.inner.binary_prompt(data)
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        self.inner.begin_batch(num_msg)
+    }
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        self.inner.supported_styles()
+    }
+    fn forget_authtok(&mut self) {
+        self.inner.forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        self.inner.forget_credentials()
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        let prompts: Vec<_> = messages
+            .iter()
+            .map(|m| m.style)
+            .filter(|style| {
+                matches!(
+                    style,
+                    PamMessageStyle::Prompt_Echo_On | PamMessageStyle::Prompt_Echo_Off
+                )
+            })
+            .collect();
+        self.timed(&prompts, |inner| inner.converse_batch(messages))
+    }
+}
+
+/// A wrapper checking new passwords against a policy before they are passed to PAM
+///
+/// Meant for changing the password (`Client::change_authentication_token`): answers to
+/// blind prompts asking for a new password are passed to `validator`. If it rejects one,
+/// the reason is shown with the `error` method of the wrapped handler and the conversation
+/// fails with `PAM_AUTHTOK_ERR`, before any module sees the password.
+///
+/// PAM does not tell prompts apart, so they are recognized by their text. By default, a
+/// prompt asks for a new password if it contains the word "new" in any case (like
+/// "New password: " and "Retype new password: ", but not "Renew token: "). This only
+/// works for English prompts: translated ones (e.g. "Neues Passwort: ") are never checked.
+/// Set a matcher for the prompts of the modules in use with `with_prompt_matcher`.
+///
+/// This gives immediate feedback, but does not replace the policy enforced by modules
+/// (e.g. `pam_pwquality`).
+pub struct PolicyConv<C, V, M = fn(&CStr) -> bool> {
+    inner: C,
+    validator: V,
+    is_new_password: M,
+}
+
+impl<C, V> PolicyConv<C, V>
+where
+    C: Conversation,
+    V: FnMut(&CStr) -> Result<(), String>,
+{
+    /// Wrap `inner`, checking new passwords with `validator`
+    pub fn new(inner: C, validator: V) -> PolicyConv<C, V> {
+        PolicyConv {
+            inner,
+            validator,
+            is_new_password: mentions_new,
+        }
+    }
+}
+
+impl<C, V, M> PolicyConv<C, V, M>
+where
+    C: Conversation,
+    V: FnMut(&CStr) -> Result<(), String>,
+    M: FnMut(&CStr) -> bool,
+{
+    /// Recognize the prompts asking for a new password with `matcher` instead
+    ///
+    /// `matcher` gets the text of each blind prompt and returns whether the answer is a
+    /// new password, which is then checked.
+    pub fn with_prompt_matcher<N>(self, matcher: N) -> PolicyConv<C, V, N>
+    where
+        N: FnMut(&CStr) -> bool,
+    {
+        PolicyConv {
+            inner: self.inner,
+            validator: self.validator,
+            is_new_password: matcher,
+        }
+    }
+
+    /// Immutable access to the wrapped handler
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Mutable access to the wrapped handler
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn check(&mut self, prompt: &CStr, password: &CStr) -> Result<(), ()> {
+        if !(self.is_new_password)(prompt) {
+            return Ok(());
+        }
+        (self.validator)(password).map_err(|reason| {
+            let reason = reason.replace('\0', " ");
+            self.inner.error(&CString::new(reason).unwrap_or_default());
+        })
+    }
+}
+
+impl<C, V, M> Conversation for PolicyConv<C, V, M>
+where
+    C: Conversation,
+    V: FnMut(&CStr) -> Result<(), String>,
+    M: FnMut(&CStr) -> bool,
+{
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        let password = self.inner.prompt_blind(msg)?;
+        self.check(msg, &password)?;
+        Ok(password)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn prompt_radio(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.inner.prompt_radio(msg)
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        self.inner.binary_prompt(data)
+    }
+    fn begin_batch(&mut self, num_msg: usize) {
+        self.inner.begin_batch(num_msg)
+    }
+    fn supported_styles(&self) -> &[PamMessageStyle] {
+        self.inner.supported_styles()
+    }
+    fn forget_authtok(&mut self) {
+        self.inner.forget_authtok()
+    }
+    fn forget_credentials(&mut self) {
+        self.inner.forget_credentials()
+    }
+    fn converse_batch(
+        &mut self,
+        messages: &[RawMessage],
+    ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+        // Rejected new passwords are wiped as well
+        let mut responses = Wiped(self.inner.converse_batch(messages)?);
+        for (message, response) in messages.iter().zip(&responses.0) {
+            if let (
+                PamMessageStyle::Prompt_Echo_Off,
+                MessageContent::Text(prompt),
+                Some(Response::Text(password)),
+            ) = (message.style, message.content, response)
+            {
+                self.check(prompt, password)
+                    .map_err(|_| PamReturnCode::AuthTok_Err)?;
+            }
+        }
+        Ok(mem::take(&mut responses.0))
+    }
+}
+
+// Whether `prompt` contains the word "new", ignoring ASCII case
+fn mentions_new(prompt: &CStr) -> bool {
+    prompt
+        .to_bytes()
+        .split(|c| !c.is_ascii_alphanumeric())
+        .any(|word| word.eq_ignore_ascii_case(b"new"))
+}
+
+/// Shorthands for wrapping a conversation handler, which can be chained
+///
+/// ```
+/// use pam_f::{ConversationExt, PamMessageStyle, PamReturnCode, PasswordConv};
+/// # fn wrap(conv: PasswordConv) {
+/// let conv = conv.limited(256).timed().filter_styles(
+///     &[PamMessageStyle::Prompt_Echo_Off, PamMessageStyle::Text_Info],
+///     PamReturnCode::Perm_Denied,
+/// );
+/// # }
+/// ```
+pub trait ConversationExt: Conversation + Sized {
+    /// Wrap in a `LimitedConv` with the given limit
+    fn limited(self, limit: usize) -> LimitedConv<Self> {
+        LimitedConv::with_limit(self, limit)
+    }
+
+    /// Wrap in a `StyleFilterConv`, see `StyleFilterConv::new`
+    fn filter_styles(
+        self,
+        allowed: &[PamMessageStyle],
+        rejection: PamReturnCode,
+    ) -> StyleFilterConv<Self> {
+        StyleFilterConv::new(self, allowed, rejection)
+    }
+
+    /// Wrap in a `TimingConv`
+    fn timed(self) -> TimingConv<Self> {
+        TimingConv::new(self)
+    }
+
+    /// Wrap in a `PolicyConv`, checking new passwords with `validator`
+    fn with_policy<V>(self, validator: V) -> PolicyConv<Self, V>
+    where
+        V: FnMut(&CStr) -> Result<(), String>,
+    {
+        PolicyConv::new(self, validator)
+    }
+
+    /// Wrap in a `BinaryConv`, answering binary prompts with `answer`
+    #[cfg(target_os = "linux")]
+    fn with_binary<F>(self, answer: F) -> BinaryConv<Self, F>
+    where
+        F: FnMut(&[u8]) -> Result<Vec<u8>, ()>,
+    {
+        BinaryConv::new(self, answer)
+    }
+}
+
+impl<C: Conversation> ConversationExt for C {}
+
+/// A conversation handler that answers prompts from environment variables
+///
+/// This is meant for non-interactive (e.g. CI) tests, so credentials don't need to be part
+/// of the source. The variables are read at prompt time, so they can still be set after
+/// the handler was created. They are never modified or removed by the handler.
+#[cfg(feature = "test-util")]
+pub struct EnvConv {
+    user_var: String,
+    password_var: String,
+}
+
+#[cfg(feature = "test-util")]
+impl EnvConv {
+    /// Create a new `EnvConv` handler reading `PAM_TEST_USER` and `PAM_TEST_PASSWORD`
+    pub fn new() -> EnvConv {
+        EnvConv::with_vars("PAM_TEST_USER", "PAM_TEST_PASSWORD")
+    }
+
+    /// Create a new `EnvConv` handler reading the given environment variables
+    pub fn with_vars<U: Into<String>, V: Into<String>>(user_var: U, password_var: V) -> EnvConv {
+        EnvConv {
+            user_var: user_var.into(),
+            password_var: password_var.into(),
+        }
+    }
+
+    fn read(var: &str) -> Result<CString, ()> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let value = std::env::var_os(var).ok_or(())?;
+        CString::new(value.into_vec()).map_err(|_| ())
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Default for EnvConv {
+    fn default() -> EnvConv {
+        EnvConv::new()
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Conversation for EnvConv {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+        EnvConv::read(&self.user_var)
+    }
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+        EnvConv::read(&self.password_var)
+    }
+    fn info(&mut self, _msg: &CStr) {}
+    fn error(&mut self, msg: &CStr) {
+        eprintln!("[PAM ERROR] {}", msg.to_string_lossy());
+    }
+}
+
+/// Builder for the message arrays PAM passes to conversation functions
+///
+/// This allows testing a `Conversation` against the exact input it gets from PAM, without
+/// a PAM stack:
+///
+/// ```
+/// use pam_f::{MessageBatchBuilder, PamMessageStyle};
+/// # use pam_f::Conversation;
+/// # struct Conv;
+/// # impl Conversation for Conv {
+/// #     fn prompt_echo(&mut self, _: &std::ffi::CStr) -> Result<std::ffi::CString, ()> {
+/// #         Ok(std::ffi::CString::new("user").unwrap())
+/// #     }
+/// #     fn prompt_blind(&mut self, _: &std::ffi::CStr) -> Result<std::ffi::CString, ()> {
+/// #         Err(())
+/// #     }
+/// #     fn info(&mut self, _: &std::ffi::CStr) {}
+/// #     fn error(&mut self, _: &std::ffi::CStr) {}
+/// # }
+///
+/// let mut batch = MessageBatchBuilder::new()
+///     .push(PamMessageStyle::Text_Info, "Welcome")
+///     .push(PamMessageStyle::Prompt_Echo_On, "login: ")
+///     .build();
+/// let answers = batch.converse(&mut Conv).unwrap();
+/// assert_eq!(answers[0], None);
+/// assert_eq!(answers[1].as_deref().unwrap().to_str(), Ok("user"));
+/// ```
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+pub struct MessageBatchBuilder {
+    entries: Vec<(PamMessageStyle, CString)>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MessageBatchBuilder {
+    /// Create an empty builder
+    pub fn new() -> MessageBatchBuilder {
+        MessageBatchBuilder::default()
+    }
+
+    /// Append a message
+    ///
+    /// # Panics
+    ///
+    /// Panics if `text` contains a nul byte.
+    pub fn push<T: Into<Vec<u8>>>(mut self, style: PamMessageStyle, text: T) -> Self {
+        let text = CString::new(text).expect("Message contains a nul byte");
+        self.entries.push((style, text));
+        self
+    }
+
+    /// Build the message array, in the layout of Linux-PAM (an array of pointers)
+    pub fn build(self) -> MessageBatch {
+        let (styles, texts): (Vec<_>, Vec<_>) = self.entries.into_iter().unzip();
+        let messages: Vec<_> = styles
+            .iter()
+            .zip(&texts)
+            .map(|(style, text)| PamMessage {
+                msg_style: *style as c_int,
+                msg: text.as_ptr() as _,
+            })
+            .collect();
+        let pointers = messages.iter().map(|m| m as *const PamMessage).collect();
+        MessageBatch {
+            _texts: texts,
+            _messages: messages,
+            pointers,
+        }
+    }
+}
+
+/// Message array built by a `MessageBatchBuilder`
+///
+/// Owns all memory the array points to, which is freed on drop.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct MessageBatch {
+    // Referenced by `pointers` only, the heap allocations stay in place on moves
+    _texts: Vec<CString>,
+    _messages: Vec<PamMessage>,
+    pointers: Vec<*const PamMessage>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MessageBatch {
+    /// The `num_msg` and `msg` arguments for a conversation function
+    ///
+    /// The pointer is valid as long as this `MessageBatch` is not dropped.
+    pub fn raw(&mut self) -> (c_int, *mut *const PamMessage) {
+        (self.pointers.len() as c_int, self.pointers.as_mut_ptr())
+    }
+
+    /// Pass the messages to `conv` like PAM would
+    ///
+    /// Returns the text answers (`None` for messages without one), after freeing the
+    /// responses like PAM does, or the error code of the conversation.
+    pub fn converse<C: Conversation>(
+        &mut self,
+        conv: &mut C,
+    ) -> Result<Vec<Option<CString>>, PamReturnCode> {
+        let (num_msg, msg) = self.raw();
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        unsafe {
+            let code = converse::<C>(num_msg, msg, &mut resp, conv as *mut C as *mut c_void);
+            if resp.is_null() {
+                return match PamReturnCode::from(code) {
+                    PamReturnCode::Success if num_msg > 0 => Err(PamReturnCode::Buf_Err),
+                    PamReturnCode::Success => Ok(Vec::new()),
+                    code => Err(code),
+                };
+            }
+            let mut answers = Vec::with_capacity(num_msg as usize);
+            for i in 0..num_msg as usize {
+                let answer = (*resp.add(i)).resp;
+                if answer.is_null() {
+                    answers.push(None);
+                } else {
+                    answers.push(Some(CStr::from_ptr(answer).to_owned()));
+                    free_c(answer as *mut c_void);
+                }
+            }
+            free_c(resp as *mut c_void);
+            match PamReturnCode::from(code) {
+                PamReturnCode::Success => Ok(answers),
+                code => Err(code),
+            }
+        }
+    }
+}
+
+/// A conversation handler for terminals, backed by `misc_conv` from libpam_misc
+///
+/// When used through `Client::with_misc_conv`, PAM calls `misc_conv` directly and the
+/// methods of this handler are bypassed. They are still implemented by passing single
+/// messages to `misc_conv`, so `MiscConv` can be used like any other `Conversation`.
+#[cfg(target_os = "linux")]
+pub struct MiscConv;
+
+#[cfg(target_os = "linux")]
+impl MiscConv {
+    fn ask(&mut self, style: PamMessageStyle, msg: &CStr) -> Result<Option<CString>, ()> {
+        let m = PamMessage {
+            msg_style: style as c_int,
+            msg: msg.as_ptr(),
+        };
+        let mut msgs = [&m as *const PamMessage];
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+
+        let code =
+            unsafe { crate::ffi::misc_conv(1, msgs.as_mut_ptr(), &mut resp, std::ptr::null_mut()) };
+        if PamReturnCode::from(code) != PamReturnCode::Success || resp.is_null() {
+            return Err(());
+        }
+        unsafe {
+            let answer = (*resp).resp;
+            let result = if answer.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(answer).to_owned())
+            };
+            // Allocated by `misc_conv` with libc, whichever allocator this crate uses
+            libc::free(answer as *mut c_void);
+            libc::free(resp as *mut c_void);
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Conversation for MiscConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.ask(PamMessageStyle::Prompt_Echo_On, msg)?.ok_or(())
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        self.ask(PamMessageStyle::Prompt_Echo_Off, msg)?.ok_or(())
+    }
+    fn info(&mut self, msg: &CStr) {
+        let _ = self.ask(PamMessageStyle::Text_Info, msg);
+    }
+    fn error(&mut self, msg: &CStr) {
+        let _ = self.ask(PamMessageStyle::Error_Msg, msg);
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn misc_pam_conv() -> pam_conv {
+    pam_conv {
+        conv: Some(crate::ffi::misc_conv),
+        appdata_ptr: std::ptr::null_mut(),
+    }
+}
+
+// PAM keeps the returned `appdata_ptr` until the transaction ends, so `conv` must not
+// move in the meantime (i.e. it has to live behind a `Box` or similar).
+pub(crate) fn into_pam_conv<C: Conversation>(conv: &mut C) -> pam_conv {
+    pam_conv {
+        conv: Some(converse::<C>),
+        appdata_ptr: conv as *mut C as *mut c_void,
+    }
+}
+
+// FIXME: verify this
+pub(crate) unsafe extern "C" fn converse<C: Conversation>(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    out_resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg < 0 {
+        return PamReturnCode::Conv_Err as c_int;
+    }
+
+    let handler = &mut *(appdata_ptr as *mut C);
+
+    // get indexed values
+    // FIXME: check this
+    let mut messages = Vec::with_capacity(num_msg as usize);
+    for i in 0..num_msg as isize {
+        match RawMessage::from_pam(&**msg.offset(i)) {
+            Some(m) => messages.push(m),
+            None => return PamReturnCode::Conv_Err as c_int,
+        }
+    }
+
+    // Unwinding into PAM is undefined behavior, so a panicking handler fails the
+    // conversation instead. Nothing has been allocated for PAM yet at this point.
+    let responses = match panic::catch_unwind(AssertUnwindSafe(|| answer(handler, &messages))) {
+        Ok(Ok(responses)) => Wiped(responses),
+        Ok(Err(code)) => return code as c_int,
+        Err(_) => return PamReturnCode::Conv_Err as c_int,
+    };
+
+    match ResponseArray::copy(&responses.0) {
+        Some(array) => {
+            *out_resp = array.into_raw();
+            PamReturnCode::Success as c_int
+        }
+        None => PamReturnCode::Buf_Err as c_int,
+    }
+}
+
+// Owns the response array built for PAM until it is handed over with `into_raw`
+//
+// Every other way out of `converse` drops it, which frees all copies made so far (wiping
+// them with the `zeroize` feature) and the array itself.
+struct ResponseArray {
+    resp: *mut PamResponse,
+    // The allocated length of each response copied so far, in order
+    lens: Vec<usize>,
+}
+
+impl ResponseArray {
+    // Copy `responses` into memory from the current allocator, `None` on failure
+    fn copy(responses: &[Option<Response>]) -> Option<ResponseArray> {
+        let resp = alloc_c(responses.len(), mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if resp.is_null() {
+            return None;
+        }
+        let mut array = ResponseArray {
+            resp,
+            lens: Vec::with_capacity(responses.len()),
+        };
+        for response in responses {
+            let (copy, len) = match response {
+                None => (std::ptr::null_mut(), 0),
+                Some(Response::Text(text)) => (dup_c(text), text.as_bytes_with_nul().len()),
+                Some(Response::Binary(data)) => (dup_bytes(data), data.len()),
+            };
+            if response.is_some() && copy.is_null() {
+                return None;
+            }
+            unsafe { (*array.resp.add(array.lens.len())).resp = copy };
+            array.lens.push(len);
+        }
+        Some(array)
+    }
+
+    // Hand the array over to PAM, which frees it from now on
+    fn into_raw(self) -> *mut PamResponse {
+        let resp = self.resp;
+        mem::forget(self);
+        resp
+    }
+}
+
+impl Drop for ResponseArray {
+    fn drop(&mut self) {
+        unsafe {
+            for (i, &len) in self.lens.iter().enumerate() {
+                let copy = (*self.resp.add(i)).resp;
+                wipe_copy(copy, len);
+                free_c(copy as *mut c_void);
+            }
+            free_c(self.resp as *mut c_void);
+        }
+    }
+}
+
+#[cfg(feature = "zeroize")]
+unsafe fn wipe_copy(copy: *mut libc::c_char, len: usize) {
+    if !copy.is_null() {
+        zeroize::Zeroize::zeroize(std::slice::from_raw_parts_mut(copy as *mut u8, len));
+    }
+}
+
+#[cfg(not(feature = "zeroize"))]
+unsafe fn wipe_copy(_copy: *mut libc::c_char, _len: usize) {}
+
+// Responses may contain passwords, so with the `zeroize` feature they are wiped once PAM
+// got its copy
+struct Wiped(Vec<Option<Response>>);
+
+#[cfg(feature = "zeroize")]
+impl Drop for Wiped {
+    fn drop(&mut self) {
+        use zeroize::Zeroize;
+        for response in self.0.iter_mut().flatten() {
+            match response {
+                Response::Text(text) => mem::take(text).into_bytes_with_nul().zeroize(),
+                Response::Binary(data) => data.zeroize(),
+            }
+        }
+    }
+}
+
+// Reject `messages` if `handler` does not support all of their styles
+fn check_styles<C: Conversation + ?Sized>(
+    handler: &mut C,
+    messages: &[RawMessage],
+) -> Result<(), PamReturnCode> {
+    if let Some(m) = messages
+        .iter()
+        .find(|m| !handler.supported_styles().contains(&m.style))
+    {
+        if let Ok(explanation) = CString::new(format!(
+            "Conversation does not support message style {}",
+            m.style
+        )) {
+            handler.error(&explanation);
+        }
+        return Err(PamReturnCode::Conv_Err);
+    }
+    Ok(())
+}
+
+// Let `handler` answer all `messages` of one call to `converse`
+fn answer<C: Conversation>(
+    handler: &mut C,
+    messages: &[RawMessage],
+) -> Result<Vec<Option<Response>>, PamReturnCode> {
+    handler.begin_batch(messages.len());
+    check_styles(handler, messages)?;
+
+    match handler.converse_batch(messages) {
+        Ok(responses) if responses.len() == messages.len() => Ok(responses),
+        Ok(_) => Err(PamReturnCode::Conv_Err),
+        Err(code) => Err(code),
+    }
+}
+
+// Dispatch a single message to the respective method of `handler`
+fn dispatch<C: Conversation + ?Sized>(
+    handler: &mut C,
+    m: &RawMessage,
+) -> Result<Option<Response>, PamReturnCode> {
+    let conv_err = |_| PamReturnCode::Conv_Err;
+
+    // match on msg_style
+    match (m.style, m.content) {
+        (PamMessageStyle::Prompt_Echo_On, MessageContent::Text(msg)) => handler
+            .prompt_echo(msg)
+            .map(|r| Some(Response::Text(r)))
+            .map_err(conv_err),
+        (PamMessageStyle::Prompt_Echo_Off, MessageContent::Text(msg)) => handler
+            .prompt_blind(msg)
+            .map(|r| Some(Response::Text(r)))
+            .map_err(conv_err),
+        #[cfg(target_os = "linux")]
+        (PamMessageStyle::Radio_Type, MessageContent::Text(msg)) => handler
+            .prompt_radio(msg)
+            .map(|r| Some(Response::Text(r)))
+            .map_err(conv_err),
+        (PamMessageStyle::Text_Info, MessageContent::Text(msg)) => {
+            handler.info(msg);
+            Ok(None)
+        }
+        (PamMessageStyle::Error_Msg, MessageContent::Text(msg)) => {
+            handler.error(msg);
+            Err(PamReturnCode::Conv_Err)
+        }
+        (_, MessageContent::Binary(data)) => match handler.binary_prompt(data) {
+            // The module trusts the length in the header, so never pass on malformed
+            // packets
+            Ok(r) if BinaryPacket::decode(&r).is_some() => Ok(Some(Response::Binary(r))),
+            _ => Err(PamReturnCode::Conv_Err),
+        },
+        // Cannot be constructed by `RawMessage::from_pam`
+        #[cfg(target_os = "linux")]
+        (PamMessageStyle::Binary_Prompt, MessageContent::Text(_)) => Err(PamReturnCode::Conv_Err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    // Run `converse` for a batch of messages like PAM would
+    fn converse_all<C: Conversation>(
+        conv: &mut C,
+        batch: &[(PamMessageStyle, &CStr)],
+    ) -> (c_int, Vec<Option<CString>>) {
+        let mut builder = MessageBatchBuilder::new();
+        for (style, text) in batch {
+            builder = builder.push(*style, text.to_bytes());
+        }
+        match builder.build().converse(conv) {
+            Ok(answers) => (PamReturnCode::Success as c_int, answers),
+            Err(code) => (code as c_int, Vec::new()),
+        }
+    }
+
+    // Run `converse` for a single message like PAM would
+    fn converse_one<C: Conversation>(conv: &mut C, style: PamMessageStyle, text: &CStr) -> c_int {
+        converse_all(conv, &[(style, text)]).0
+    }
+
+    #[test]
+    fn raw_message_views() {
+        let text = CString::new("Password: ").unwrap();
+        let m = PamMessage {
+            msg_style: PamMessageStyle::Prompt_Echo_Off as c_int,
+            msg: text.as_ptr() as _,
+        };
+        let raw = unsafe { RawMessage::from_pam(&m) }.unwrap();
+        assert_eq!(raw.style, PamMessageStyle::Prompt_Echo_Off);
+        assert_eq!(raw.content, MessageContent::Text(&text));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn raw_message_binary_views() {
+        // 4 byte length, 1 byte type and payload, followed by garbage
+        let packet = [0, 0, 0, 8, 1, b'a', 0, b'c', 0xff, 0xff];
+        let m = PamMessage {
+            msg_style: PamMessageStyle::Binary_Prompt as c_int,
+            msg: packet.as_ptr() as _,
+        };
+        let raw = unsafe { RawMessage::from_pam(&m) }.unwrap();
+        assert_eq!(raw.style, PamMessageStyle::Binary_Prompt);
+        assert_eq!(raw.content, MessageContent::Binary(&packet[..8]));
+
+        let short = [0u8, 0, 0, 4, 1];
+        let m = PamMessage {
+            msg_style: PamMessageStyle::Binary_Prompt as c_int,
+            msg: short.as_ptr() as _,
+        };
+        assert_eq!(unsafe { RawMessage::from_pam(&m) }, None);
+    }
+
+    #[test]
+    fn binary_packets() {
+        let payload = [0xde, 0xad, 0, 0xef];
+        let packet = BinaryPacket::new(7, &payload).encode();
+        assert_eq!(packet, [0, 0, 0, 9, 7, 0xde, 0xad, 0, 0xef]);
+        assert_eq!(
+            BinaryPacket::decode(&packet),
+            Some(BinaryPacket::new(7, &payload))
+        );
+
+        // The length is big endian and includes the header
+        let payload = vec![b'x'; 0x0102];
+        let packet = BinaryPacket::new(1, &payload).encode();
+        assert_eq!(packet[..5], [0, 0, 0x01, 0x07, 1]);
+        assert_eq!(BinaryPacket::decode(&packet).unwrap().payload, &payload[..]);
+
+        let empty = BinaryPacket::new(0, &[]).encode();
+        assert_eq!(empty, [0, 0, 0, 5, 0]);
+        assert_eq!(BinaryPacket::decode(&empty).unwrap().payload, &[] as &[u8]);
+
+        assert_eq!(BinaryPacket::decode(&[0, 0, 0, 5]), None);
+        assert_eq!(BinaryPacket::decode(&[0, 0, 0, 4, 0]), None);
+        assert_eq!(BinaryPacket::decode(&[0, 0, 0, 6, 0]), None);
+        assert_eq!(BinaryPacket::decode(&[0, 0, 0, 5, 0, 1]), None);
+        assert_eq!(BinaryPacket::decode(&[5, 0, 0, 0, 0]), None);
+    }
+
+    #[derive(Default)]
+    struct CountingConv {
+        batches: usize,
+        messages: usize,
+    }
+
+    impl Conversation for CountingConv {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            CString::new("user").map_err(|_| ())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            CString::new("secret").map_err(|_| ())
+        }
+        fn info(&mut self, _msg: &CStr) {}
+        fn error(&mut self, _msg: &CStr) {}
+        fn begin_batch(&mut self, num_msg: usize) {
+            self.batches += 1;
+            self.messages += num_msg;
+        }
+    }
+
+    #[test]
+    fn converse_reports_batches() {
+        let mut conv = CountingConv::default();
+        let prompt = CString::new("login: ").unwrap();
+        converse_one(&mut conv, PamMessageStyle::Prompt_Echo_On, &prompt);
+        converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &prompt);
+        assert_eq!(conv.batches, 2);
+        assert_eq!(conv.messages, 2);
+    }
+
+    #[test]
+    fn password_conv_duplicate() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        let mut copy = conv.duplicate();
+        drop(conv);
+
+        let prompt = CString::new("Password: ").unwrap();
+        assert_eq!(copy.prompt_echo(&prompt), Ok(CString::new("user").unwrap()));
+        assert_eq!(
+            copy.prompt_blind(&prompt),
+            Ok(CString::new("secret").unwrap())
+        );
+    }
+
+    #[test]
+    fn password_conv_extra_answers() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        conv.set_extra_blind_answers(["1234"]);
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+        let pin = CString::new("PIN: ").unwrap();
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, pin.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, pin.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+        ];
+        let answer = |s: &str| Some(CString::new(s).unwrap());
+        let expected = vec![
+            answer("user"),
+            answer("secret"),
+            answer("1234"),
+            answer("secret"),
+            answer("user"),
+        ];
+
+        // The order starts over with each batch
+        assert_eq!(converse_all(&mut conv, &batch), (0, expected.clone()));
+        assert_eq!(converse_all(&mut conv, &batch), (0, expected));
+
+        conv.set_extra_echo_answer(Some("token"));
+        let answers = converse_all(&mut conv, &batch).1;
+        assert_eq!(answers[0], answer("user"));
+        assert_eq!(answers[4], answer("token"));
+
+        conv.forget_authtok();
+        let answers = converse_all(&mut conv, &batch).1;
+        assert_eq!(answers[2], answer(""));
+    }
+
+    #[test]
+    fn password_conv_require_non_empty() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "");
+        let prompt = CString::new("Password: ").unwrap();
+        assert_eq!(conv.prompt_blind(&prompt), Ok(CString::default()));
+
+        conv.require_non_empty(true);
+        assert_eq!(conv.prompt_blind(&prompt), Err(()));
+        assert!(conv.prompt_echo(&prompt).is_ok());
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &prompt);
+        assert_eq!(
+            PamReturnCode::from(code),
+            PamReturnCode::AuthTok_Recovery_Err
+        );
+    }
+
+    #[test]
+    fn limited_conv_rejects_long_responses() {
+        let mut inner = PasswordConv::new();
+        inner.set_credentials(
+            "user",
+            "x".repeat(LimitedConv::<PasswordConv>::DEFAULT_LIMIT),
+        );
+        let mut conv = LimitedConv::new(inner);
+        let prompt = CString::new("Password: ").unwrap();
+        assert!(conv.prompt_blind(&prompt).is_ok());
+
+        conv.inner_mut().set_credentials("user", "xx");
+        let mut conv = LimitedConv::with_limit(conv.into_inner(), 1);
+        assert_eq!(conv.prompt_blind(&prompt), Err(()));
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &prompt);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn env_conv_reads_at_prompt_time() {
+        let mut conv = EnvConv::with_vars("PAM_F_ENV_CONV_USER", "PAM_F_ENV_CONV_PASSWORD");
+        let prompt = CString::new("login: ").unwrap();
+        assert_eq!(conv.prompt_echo(&prompt), Err(()));
+
+        std::env::set_var("PAM_F_ENV_CONV_USER", "user");
+        std::env::set_var("PAM_F_ENV_CONV_PASSWORD", "secret");
+        assert_eq!(conv.prompt_echo(&prompt), Ok(CString::new("user").unwrap()));
+        assert_eq!(
+            conv.prompt_blind(&prompt),
+            Ok(CString::new("secret").unwrap())
+        );
+        assert_eq!(
+            std::env::var("PAM_F_ENV_CONV_PASSWORD").as_deref(),
+            Ok("secret")
+        );
+    }
+
+    // Answers all prompts of a batch with the position of the prompt in the batch
+    struct BatchConv;
+
+    impl Conversation for BatchConv {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Err(())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Err(())
+        }
+        fn info(&mut self, _msg: &CStr) {}
+        fn error(&mut self, _msg: &CStr) {}
+        fn converse_batch(
+            &mut self,
+            messages: &[RawMessage],
+        ) -> Result<Vec<Option<Response>>, PamReturnCode> {
+            Ok(messages
+                .iter()
+                .enumerate()
+                .map(|(i, m)| match m.style {
+                    PamMessageStyle::Text_Info => None,
+                    _ => Some(Response::Text(
+                        CString::new(format!("{}/{}", i, messages.len())).unwrap(),
+                    )),
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn converse_batch() {
+        let login = CString::new("login: ").unwrap();
+        let info = CString::new("Welcome").unwrap();
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Text_Info, info.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, login.as_c_str()),
+        ];
+        let (code, answers) = converse_all(&mut BatchConv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(
+            answers,
+            vec![
+                Some(CString::new("0/3").unwrap()),
+                None,
+                Some(CString::new("2/3").unwrap())
+            ]
+        );
+
+        // The default implementation dispatches every message
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        let (code, answers) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(
+            answers,
+            vec![
+                Some(CString::new("user").unwrap()),
+                None,
+                Some(CString::new("secret").unwrap())
+            ]
+        );
+    }
+
+    struct PanickingConv;
+
+    impl Conversation for PanickingConv {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Ok(CString::new("user").unwrap())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            panic!("poisoned");
+        }
+        fn info(&mut self, _msg: &CStr) {}
+        fn error(&mut self, _msg: &CStr) {}
+    }
+
+    #[test]
+    fn converse_catches_panics() {
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+        ];
+        let (code, answers) = converse_all(&mut PanickingConv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert!(answers.is_empty());
+    }
+
+    #[test]
+    fn converse_frees_on_all_paths() {
+        use crate::alloc::tests::{fail_allocations_after, live_allocations};
+
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+        ];
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        let before = live_allocations();
+
+        // Success, PAM (here `MessageBatch::converse`) frees the responses
+        let (code, answers) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(live_allocations(), before);
+
+        // Handler failing in the middle of the batch
+        let failing = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Error_Msg, login.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+        ];
+        let (code, _) = converse_all(&mut conv, &failing);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert_eq!(live_allocations(), before);
+
+        // Panicking handler
+        let (code, _) = converse_all(&mut PanickingConv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert_eq!(live_allocations(), before);
+
+        // Copying the array, the first or the second response fails
+        for allowed in 0..3 {
+            fail_allocations_after(Some(allowed));
+            let (code, _) = converse_all(&mut conv, &batch);
+            fail_allocations_after(None);
+            assert_eq!(PamReturnCode::from(code), PamReturnCode::Buf_Err);
+            assert_eq!(live_allocations(), before);
+        }
+
+        // Invalid number of messages
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        let code = unsafe {
+            converse::<PasswordConv>(
+                -1,
+                std::ptr::null_mut(),
+                &mut resp,
+                &mut conv as *mut PasswordConv as *mut c_void,
+            )
+        };
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert!(resp.is_null());
+        assert_eq!(live_allocations(), before);
+    }
+
+    #[test]
+    fn message_batch_layout() {
+        let mut batch = MessageBatchBuilder::new()
+            .push(PamMessageStyle::Error_Msg, "failed")
+            .push(PamMessageStyle::Prompt_Echo_Off, "Password: ")
+            .build();
+        let (num_msg, msg) = batch.raw();
+        assert_eq!(num_msg, 2);
+        unsafe {
+            let second = &**msg.add(1);
+            assert_eq!(second.msg_style, PamMessageStyle::Prompt_Echo_Off as c_int);
+            assert_eq!(CStr::from_ptr(second.msg).to_str(), Ok("Password: "));
+        }
+
+        let mut empty = MessageBatchBuilder::new().build();
+        assert_eq!(empty.raw().0, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn converse_rejects_unsupported_styles() {
+        let (mut conv, prompts, _answers) = ChannelConv::new();
+        let packet = [0u8, 0, 0, 5, 1];
+        let m = PamMessage {
+            msg_style: PamMessageStyle::Binary_Prompt as c_int,
+            msg: packet.as_ptr() as _,
+        };
+        let mut msgs = [&m as *const PamMessage];
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        let code = unsafe {
+            converse::<ChannelConv>(
+                1,
+                msgs.as_mut_ptr(),
+                &mut resp,
+                &mut conv as *mut ChannelConv as *mut c_void,
+            )
+        };
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert!(resp.is_null());
+
+        match prompts.try_recv().unwrap() {
+            ChannelMessage::Error { text } => {
+                assert!(text.to_str().unwrap().contains("Binary_Prompt"))
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+        assert!(prompts.try_recv().is_err());
+    }
+
+    // Run `converse` for a single binary prompt and return the response packet
+    #[cfg(target_os = "linux")]
+    fn converse_binary<C: Conversation>(conv: &mut C, packet: &[u8]) -> Option<Vec<u8>> {
+        let m = PamMessage {
+            msg_style: PamMessageStyle::Binary_Prompt as c_int,
+            msg: packet.as_ptr() as _,
+        };
+        let mut msgs = [&m as *const PamMessage];
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        unsafe {
+            let code = converse::<C>(
+                1,
+                msgs.as_mut_ptr(),
+                &mut resp,
+                conv as *mut C as *mut c_void,
+            );
+            if PamReturnCode::from(code) != PamReturnCode::Success {
+                return None;
+            }
+            let answer = (*resp).resp as *const u8;
+            let header = std::slice::from_raw_parts(answer, 4);
+            let len = BinaryPacket::stated_len(header).unwrap();
+            let packet = std::slice::from_raw_parts(answer, len).to_vec();
+            free_c(answer as *mut c_void);
+            free_c(resp as *mut c_void);
+            Some(packet)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binary_conv_round_trip() {
+        let mut conv = BinaryConv::new(PasswordConv::new(), |challenge: &[u8]| {
+            // Sign the challenge by reversing its payload
+            let mut payload = BinaryPacket::decode(challenge).ok_or(())?.payload.to_vec();
+            payload.reverse();
+            Ok(BinaryPacket::new(2, &payload).encode())
+        });
+        assert!(conv
+            .supported_styles()
+            .contains(&PamMessageStyle::Binary_Prompt));
+
+        let challenge = [0u8, 0, 0, 9, 1, 0xde, 0xad, 0, 0xef];
+        assert_eq!(
+            converse_binary(&mut conv, &challenge),
+            Some(vec![0, 0, 0, 9, 2, 0xef, 0, 0xad, 0xde])
+        );
+
+        // Text prompts still go to the inner handler
+        conv.inner_mut().set_credentials("user", "secret");
+        let prompt = CString::new("login: ").unwrap();
+        assert_eq!(conv.prompt_echo(&prompt), Ok(CString::new("user").unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binary_conv_passes_batches_on() {
+        let mut inner = PasswordConv::new();
+        inner.set_credentials("user", "secret");
+        inner.require_non_empty(true);
+        let mut conv = BinaryConv::new(inner, |_: &[u8]| Ok(BinaryPacket::new(2, b"ok").encode()));
+        let challenge = [0u8, 0, 0, 5, 1];
+        let prompt = CString::new("Password: ").unwrap();
+        let batch = [
+            RawMessage {
+                style: PamMessageStyle::Prompt_Echo_Off,
+                content: MessageContent::Text(&prompt),
+            },
+            RawMessage {
+                style: PamMessageStyle::Binary_Prompt,
+                content: MessageContent::Binary(&challenge),
+            },
+            RawMessage {
+                style: PamMessageStyle::Text_Info,
+                content: MessageContent::Text(&prompt),
+            },
+        ];
+        assert_eq!(
+            conv.converse_batch(&batch),
+            Ok(vec![
+                Some(Response::Text(CString::new("secret").unwrap())),
+                Some(Response::Binary(vec![0, 0, 0, 7, 2, b'o', b'k'])),
+                None,
+            ])
+        );
+
+        // The inner handler still decides how its messages fail
+        conv.inner_mut().set_credentials("user", "");
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &prompt);
+        assert_eq!(
+            PamReturnCode::from(code),
+            PamReturnCode::AuthTok_Recovery_Err
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binary_conv_rejects_bad_packets() {
+        let challenge = [0u8, 0, 0, 5, 1];
+        let mut conv = BinaryConv::new(PasswordConv::new(), |_: &[u8]| Ok(vec![0, 0, 0, 9, 2]));
+        assert_eq!(converse_binary(&mut conv, &challenge), None);
+        let mut conv = BinaryConv::new(PasswordConv::new(), |_: &[u8]| Ok(vec![0, 0, 5]));
+        assert_eq!(converse_binary(&mut conv, &challenge), None);
+    }
+
+    #[test]
+    fn style_filter_conv() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        let mut conv = StyleFilterConv::new(
+            conv,
+            &[PamMessageStyle::Prompt_Echo_Off, PamMessageStyle::Text_Info],
+            PamReturnCode::Perm_Denied,
+        );
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+
+        let (code, answers) =
+            converse_all(&mut conv, &[(PamMessageStyle::Prompt_Echo_Off, &password)]);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers, vec![Some(CString::new("secret").unwrap())]);
+
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+        ];
+        let (code, _) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Perm_Denied);
+    }
+
+    #[test]
+    fn timing_conv() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        let mut conv = conv.limited(4).timed();
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Text_Info, login.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+        ];
+
+        // Answers exceeding the limit of the wrapped handler are timed as well
+        let (code, _) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        let styles: Vec<_> = conv.timings().iter().map(|(style, _)| *style).collect();
+        assert_eq!(
+            styles,
+            [
+                PamMessageStyle::Prompt_Echo_On,
+                PamMessageStyle::Prompt_Echo_Off
+            ]
+        );
+
+        conv.clear_timings();
+        conv.inner_mut().inner_mut().set_credentials("user", "pass");
+        let (code, answers) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers[2], Some(CString::new("pass").unwrap()));
+        assert_eq!(conv.timings().len(), 2);
+    }
+
+    #[test]
+    fn timing_conv_passes_batches_on() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "secret");
+        let mut conv = conv
+            .filter_styles(
+                &[PamMessageStyle::Prompt_Echo_Off],
+                PamReturnCode::Perm_Denied,
+            )
+            .timed();
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+
+        let (code, _) = converse_all(&mut conv, &[(PamMessageStyle::Prompt_Echo_On, &login)]);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Perm_Denied);
+        assert_eq!(conv.timings().len(), 1);
+
+        let (code, answers) =
+            converse_all(&mut conv, &[(PamMessageStyle::Prompt_Echo_Off, &password)]);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers, vec![Some(CString::new("secret").unwrap())]);
+        assert_eq!(conv.timings()[1].0, PamMessageStyle::Prompt_Echo_Off);
+    }
+
+    #[cfg(feature = "secrecy")]
+    #[test]
+    fn password_conv_secret() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("user", "plain");
+        conv.set_password_secret(secrecy::SecretString::new("secret".to_string()));
+        let password = CString::new("Password: ").unwrap();
+        let (code, answers) =
+            converse_all(&mut conv, &[(PamMessageStyle::Prompt_Echo_Off, &password)]);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers, vec![Some(CString::new("secret").unwrap())]);
+        assert!(conv.passwd.is_empty());
+        assert_eq!(conv.duplicate().password(), "secret");
+
+        conv.forget_authtok();
+        assert_eq!(conv.password(), "");
+        assert_eq!(conv.login, "user");
+    }
+
+    // Answers blind prompts with a fixed password and records errors
+    struct NewPasswordConv {
+        password: CString,
+        errors: Vec<CString>,
+    }
+
+    impl Conversation for NewPasswordConv {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Err(())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Ok(self.password.clone())
+        }
+        fn info(&mut self, _msg: &CStr) {}
+        fn error(&mut self, msg: &CStr) {
+            self.errors.push(msg.to_owned());
+        }
+    }
+
+    #[test]
+    fn policy_conv() {
+        let conv = NewPasswordConv {
+            password: CString::new("short").unwrap(),
+            errors: Vec::new(),
+        };
+        let mut conv = conv.with_policy(|password| {
+            if password.to_bytes().len() < 8 {
+                Err("The password must have at least 8 characters".to_string())
+            } else {
+                Ok(())
+            }
+        });
+        let current = CString::new("Current password: ").unwrap();
+        let new = CString::new("New password: ").unwrap();
+
+        // Only new passwords are checked
+        let (code, answers) =
+            converse_all(&mut conv, &[(PamMessageStyle::Prompt_Echo_Off, &current)]);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers, vec![Some(CString::new("short").unwrap())]);
+        assert!(conv.inner().errors.is_empty());
+
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &new);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::AuthTok_Err);
+        assert_eq!(
+            conv.inner().errors,
+            [CString::new("The password must have at least 8 characters").unwrap()]
+        );
+        assert_eq!(conv.prompt_blind(&new), Err(()));
+
+        conv.inner_mut().password = CString::new("long enough").unwrap();
+        let retype = CString::new("Retype NEW password: ").unwrap();
+        let (code, _) = converse_all(&mut conv, &[(PamMessageStyle::Prompt_Echo_Off, &retype)]);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+
+        // Only the word counts, other languages need a matcher
+        conv.inner_mut().password = CString::new("short").unwrap();
+        let renew = CString::new("Renew token: ").unwrap();
+        assert!(conv.prompt_blind(&renew).is_ok());
+        let german = CString::new("Neues Passwort: ").unwrap();
+        assert!(conv.prompt_blind(&german).is_ok());
+        let mut conv = conv.with_prompt_matcher(|prompt| prompt.to_bytes().starts_with(b"Neu"));
+        assert_eq!(conv.prompt_blind(&german), Err(()));
+        assert!(conv.prompt_blind(&new).is_ok());
+    }
+
+    // An in-memory store, counting its lookups
+    #[derive(Default)]
+    struct MapStore {
+        secrets: std::collections::HashMap<String, CString>,
+        lookups: usize,
+    }
+
+    impl SecretStore for MapStore {
+        fn get(&mut self, user: &str, _prompt: &CStr) -> Option<CString> {
+            self.lookups += 1;
+            self.secrets.get(user).cloned()
+        }
+    }
+
+    #[test]
+    fn keyring_conv() {
+        let mut store = MapStore::default();
+        store
+            .secrets
+            .insert("alice".into(), CString::new("secret").unwrap());
+        let mut conv = KeyringConv::new(store);
+        let login = CString::new("login: ").unwrap();
+        let password = CString::new("Password: ").unwrap();
+        let batch = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, password.as_c_str()),
+        ];
+
+        // No user to look up
+        let (code, _) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert_eq!(conv.store().lookups, 0);
+
+        conv.set_user(Some("alice"));
+        let (code, answers) = converse_all(&mut conv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(
+            answers,
+            vec![
+                Some(CString::new("alice").unwrap()),
+                Some(CString::new("secret").unwrap())
+            ]
+        );
+
+        // A miss fails the prompt
+        conv.set_user(Some("bob"));
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &password);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        assert_eq!(conv.store().lookups, 2);
+
+        conv.forget_credentials();
+        assert_eq!(conv.user(), None);
+    }
+
+    #[test]
+    fn null_conv() {
+        let text = CString::new("Password: ").unwrap();
+        for &style in &[
+            PamMessageStyle::Prompt_Echo_Off,
+            PamMessageStyle::Prompt_Echo_On,
+        ] {
+            let code = converse_one(&mut NullConv, style, &text);
+            assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        }
+
+        let batch = [
+            (PamMessageStyle::Text_Info, text.as_c_str()),
+            (PamMessageStyle::Text_Info, text.as_c_str()),
+        ];
+        let (code, answers) = converse_all(&mut NullConv, &batch);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Success);
+        assert_eq!(answers, vec![None, None]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn style_filter_conv_rejects_unsupported_styles() {
+        // Allowed, but not supported by `PasswordConv`
+        let mut conv = StyleFilterConv::new(
+            PasswordConv::new(),
+            &[PamMessageStyle::Radio_Type],
+            PamReturnCode::Perm_Denied,
+        );
+        let question = CString::new("Continue?").unwrap();
+        let code = converse_one(&mut conv, PamMessageStyle::Radio_Type, &question);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_On, &question);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Perm_Denied);
+    }
+
+    #[test]
+    fn unknown_styles_are_malformed() {
+        let text = CString::new("login: ").unwrap();
+        let m = PamMessage {
+            msg_style: 0x4242,
+            msg: text.as_ptr() as _,
+        };
+        assert_eq!(unsafe { RawMessage::from_pam(&m) }, None);
+    }
+
+    #[test]
+    fn channel_conv_drop_closes_prompts() {
+        let (conv, prompts, _answers) = ChannelConv::new();
+        drop(conv);
+        assert!(prompts.recv().is_err());
+    }
+
+    #[test]
+    fn channel_conv_dropped_answers_is_conv_err() {
+        let (mut conv, prompts, answers) = ChannelConv::new();
+        let ui = thread::spawn(move || {
+            let message = prompts.recv().unwrap();
+            assert!(matches!(
+                message,
+                ChannelMessage::Prompt {
+                    style: PamMessageStyle::Prompt_Echo_Off,
+                    ..
+                }
+            ));
+            drop(answers);
+        });
+        let prompt = CString::new("Password: ").unwrap();
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &prompt);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        ui.join().unwrap();
+    }
+
+    #[test]
+    fn channel_conv_prompt_timeout() {
+        let (mut conv, prompts, answers) = ChannelConv::new();
+        assert_eq!(conv.timeout(), None);
+        conv.set_timeout(Some(Duration::from_millis(50)));
+        let (late_tx, late_rx) = channel();
+        let ui = thread::spawn(move || {
+            assert!(prompts.recv().unwrap().expects_answer());
+            assert_eq!(prompts.recv().unwrap(), ChannelMessage::Cancelled { id: 0 });
+            // Answer only after PAM gave up
+            late_rx.recv().unwrap();
+            answers
+                .send((0, CString::new("too late").unwrap()))
+                .unwrap();
+            let text = CString::new("login: ").unwrap();
+            assert_eq!(
+                prompts.recv().unwrap(),
+                ChannelMessage::Prompt {
+                    id: 1,
+                    style: PamMessageStyle::Prompt_Echo_On,
+                    text
+                }
+            );
+            answers.send((1, CString::new("user").unwrap())).unwrap();
+        });
+        let prompt = CString::new("Password: ").unwrap();
+        let code = converse_one(&mut conv, PamMessageStyle::Prompt_Echo_Off, &prompt);
+        assert_eq!(PamReturnCode::from(code), PamReturnCode::Conv_Err);
+        late_tx.send(()).unwrap();
+
+        // The late answer is not mistaken for the answer to the next prompt
+        conv.set_timeout(None);
+        let prompt = CString::new("login: ").unwrap();
+        assert_eq!(conv.prompt_echo(&prompt), Ok(CString::new("user").unwrap()));
+        ui.join().unwrap();
+    }
+
+    #[test]
+    fn channel_conv_answers_prompt() {
+        let (mut conv, prompts, answers) = ChannelConv::new();
+        let ui = thread::spawn(move || {
+            let id = match prompts.recv().unwrap() {
+                ChannelMessage::Prompt { id, .. } => id,
+                message => panic!("{:?}", message),
+            };
+            // Answers to other prompts are ignored
+            answers
+                .send((id + 1, CString::new("other").unwrap()))
+                .unwrap();
+            answers.send((id, CString::new("user").unwrap())).unwrap();
+            for message in prompts {
+                assert!(!message.expects_answer());
+                if let ChannelMessage::Info { text } = message {
+                    assert_eq!(text.to_str(), Ok("Welcome"));
+                }
+            }
+        });
+        let prompt = CString::new("login: ").unwrap();
+        assert_eq!(conv.prompt_echo(&prompt), Ok(CString::new("user").unwrap()));
+        conv.info(&CString::new("Welcome").unwrap());
+        drop(conv);
+        ui.join().unwrap();
+    }
+
+    #[test]
+    fn channel_conv_keepalive() {
+        let (mut conv, prompts, answers) = ChannelConv::new();
+        conv.set_keepalive(Some(Duration::from_millis(10)));
+        assert_eq!(conv.keepalive(), Some(Duration::from_millis(10)));
+        let ui = thread::spawn(move || {
+            assert!(prompts.recv().unwrap().expects_answer());
+            assert_eq!(prompts.recv().unwrap(), ChannelMessage::Keepalive);
+            answers.send((0, CString::new("secret").unwrap())).unwrap();
+            // The UI going away is noticed on the next keepalive
+            let message = prompts
+                .iter()
+                .find(|message| *message != ChannelMessage::Keepalive);
+            assert!(message.unwrap().expects_answer());
+        });
+        let prompt = CString::new("Password: ").unwrap();
+        assert_eq!(
+            conv.prompt_blind(&prompt),
+            Ok(CString::new("secret").unwrap())
+        );
+        assert_eq!(conv.prompt_blind(&prompt), Err(()));
+        ui.join().unwrap();
+    }
 }
@@ -2,7 +2,9 @@
 
 //! Types defined by Linux-PAM
 //!
-//! This modules contains struct and enum definitions used by `pam-sys`.
+//! This modules contains struct and enum definitions used by `pam-sys`. The values are
+//! taken from the PAM headers of the target, so they differ between Linux-PAM and
+//! OpenPAM (e.g. on macOS). Variants for Linux-PAM extensions are only available on Linux.
 
 use pam_macros_f::pam_enum;
 
@@ -104,10 +106,12 @@ pub enum PamReturnCode {
     Bad_Item,
 
     /// conversation function is event driven and data is not available yet
+    #[cfg(target_os = "linux")]
     Conv_Again,
 
     /// please call this function again to complete authentication stack.
     /// Before calling again as isize, verify that conversation is completed
+    #[cfg(target_os = "linux")]
     Incomplete,
 }
 
@@ -117,6 +121,56 @@ impl std::fmt::Display for PamReturnCode {
     }
 }
 
+impl PamReturnCode {
+    /// Classify this return code into a coarse `PamCategory`
+    pub fn category(self) -> PamCategory {
+        use PamReturnCode::*;
+
+        match self {
+            Success => PamCategory::Success,
+
+            Auth_Err | User_Unknown | Cred_Insufficient | AuthTok_Err | AuthTok_Recovery_Err
+            | Try_Again => PamCategory::UserError,
+
+            Perm_Denied
+            | MaxTries
+            | New_Authtok_Reqd
+            | Acct_Expired
+            | Cred_Expired
+            | AuthTok_Expired
+            | AuthTok_Disable_Aging => PamCategory::AccountError,
+
+            System_Err | Open_Err | Symbol_Err | Service_Err | Buf_Err | Authinfo_Unavail
+            | Session_Err | Cred_Unavail | Cred_Err | No_Module_Data | Conv_Err
+            | AuthTok_Lock_Busy | Ignore | Abort | Module_Unknown | Bad_Item => {
+                PamCategory::SystemError
+            }
+            #[cfg(target_os = "linux")]
+            Conv_Again | Incomplete => PamCategory::SystemError,
+        }
+    }
+}
+
+/// Coarse categories of `PamReturnCode`s
+///
+/// Use this to decide how to react to a failed PAM call without matching on every
+/// single return code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PamCategory {
+    /// The call succeeded
+    Success,
+
+    /// The supplied credentials were wrong or insufficient, retrying might help
+    UserError,
+
+    /// The account or its policy denies access, retrying won't help but the user
+    /// should be told why
+    AccountError,
+
+    /// PAM, a module or the system failed, this should be logged
+    SystemError,
+}
+
 /// The Linux-PAM flags
 #[pam_enum]
 pub enum PamFlag {
@@ -171,6 +225,12 @@ impl std::fmt::Display for PamFlag {
     }
 }
 
+impl std::convert::From<PamFlag> for i32 {
+    fn from(flag: PamFlag) -> i32 {
+        flag as i32
+    }
+}
+
 /// The Linux-PAM item types
 ///
 /// These defines are used by `pam_set_item()` `and pam_get_item()`.
@@ -206,15 +266,19 @@ pub enum PamItemType {
     User_Prompt,
 
     /// app supplied function to override failure delays
+    #[cfg(target_os = "linux")]
     Fail_Delay,
 
     /// X display name
+    #[cfg(target_os = "linux")]
     XDisplay,
 
     /// X server authentication data
+    #[cfg(target_os = "linux")]
     XAuthData,
 
     /// The type for pam_get_authtok
+    #[cfg(target_os = "linux")]
     AuthTok_Type,
 }
 
@@ -231,6 +295,14 @@ pub enum PamMessageStyle {
     Prompt_Echo_Off,
     Error_Msg,
     Text_Info,
+
+    /// Radio button style question (Linux-PAM extension)
+    #[cfg(target_os = "linux")]
+    Radio_Type,
+
+    /// Binary prompt for machine-machine communication (Linux-PAM extension)
+    #[cfg(target_os = "linux")]
+    Binary_Prompt,
 }
 
 impl std::fmt::Display for PamMessageStyle {
@@ -238,3 +310,68 @@ impl std::fmt::Display for PamMessageStyle {
         f.write_str(&format!("{:?} ({})", self, *self as i32))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Number of return values defined by the PAM headers
+    #[cfg(target_os = "linux")]
+    const RETURN_VALUES: i32 = pam_sys::_PAM_RETURN_VALUES;
+    #[cfg(not(target_os = "linux"))]
+    const RETURN_VALUES: i32 = pam_sys::PAM_NUM_ERRORS;
+
+    #[test]
+    fn return_code_categories() {
+        for code in 0..RETURN_VALUES {
+            let code = PamReturnCode::from(code);
+            assert_eq!(
+                code.category() == PamCategory::Success,
+                code == PamReturnCode::Success,
+                "{}",
+                code
+            );
+        }
+        assert_eq!(PamReturnCode::Auth_Err.category(), PamCategory::UserError);
+        assert_eq!(
+            PamReturnCode::Acct_Expired.category(),
+            PamCategory::AccountError
+        );
+        assert_eq!(PamReturnCode::Buf_Err.category(), PamCategory::SystemError);
+    }
+
+    // Values from <security/_pam_types.h> of Linux-PAM
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_pam_values() {
+        assert_eq!(PamReturnCode::Success as i32, 0);
+        assert_eq!(PamReturnCode::Perm_Denied as i32, 6);
+        assert_eq!(PamReturnCode::New_Authtok_Reqd as i32, 12);
+        assert_eq!(PamReturnCode::Bad_Item as i32, 29);
+        assert_eq!(PamReturnCode::Incomplete as i32, 31);
+        assert_eq!(PamFlag::Silent as i32, 0x8000);
+        assert_eq!(PamFlag::Delete_Cred as i32, 0x4);
+        assert_eq!(PamFlag::Change_Expired_AuthTok as i32, 0x20);
+        assert_eq!(PamItemType::Conv as i32, 5);
+        assert_eq!(PamItemType::AuthTok_Type as i32, 13);
+        assert_eq!(PamMessageStyle::Binary_Prompt as i32, 7);
+        assert_eq!(PamReturnCode::from(12), PamReturnCode::New_Authtok_Reqd);
+    }
+
+    // Values from <security/pam_constants.h> of OpenPAM
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn openpam_values() {
+        assert_eq!(PamReturnCode::Success as i32, 0);
+        assert_eq!(PamReturnCode::Perm_Denied as i32, 7);
+        assert_eq!(PamReturnCode::New_Authtok_Reqd as i32, 10);
+        assert_eq!(PamReturnCode::Bad_Item as i32, 31);
+        assert_eq!(PamFlag::Silent as i32, 0x8000_0000_u32 as i32);
+        assert_eq!(PamFlag::Delete_Cred as i32, 0x2);
+        assert_eq!(PamFlag::Change_Expired_AuthTok as i32, 0x4);
+        assert_eq!(PamItemType::Conv as i32, 5);
+        assert_eq!(PamItemType::User_Prompt as i32, 9);
+        assert_eq!(PamMessageStyle::Text_Info as i32, 4);
+        assert_eq!(PamReturnCode::from(10), PamReturnCode::New_Authtok_Reqd);
+    }
+}
@@ -22,12 +22,14 @@ impl Iterator for PamEnvList {
 }
 
 impl PamEnvList {
+    // Takes ownership of the list returned by `pam_getenvlist`, which is null if PAM
+    // failed to allocate it
     pub(crate) fn from_ptr(ptr: *const *const c_char) -> PamEnvList {
         let mut result = Vec::new();
 
-        unsafe {
-            let mut current = ptr;
-            if !current.is_null() {
+        if !ptr.is_null() {
+            unsafe {
+                let mut current = ptr;
                 while !(*current).is_null() {
                     if let Some(key_value) = parse_env_line(CStr::from_ptr(*current).to_bytes()) {
                         result.push(key_value);
@@ -35,9 +37,10 @@ impl PamEnvList {
                     current = current.add(1);
                 }
             }
+            // `pam_misc_drop_env` does not accept null
+            drop_env_list(ptr);
         }
 
-        drop_env_list(ptr);
         PamEnvList {
             inner: result.into_iter(),
         }
@@ -54,7 +57,8 @@ fn parse_env_line(input: &[u8]) -> Option<(OsString, OsString)> {
     if input.is_empty() {
         return None;
     }
-    let pos = memchr(b'=', input).map(|p| p + 1);
+    // Search past the first byte, which belongs to the name even if it is an equals sign
+    let pos = memchr(b'=', &input[1..]).map(|p| p + 1);
     pos.map(|p| {
         (
             OsStringExt::from_vec(input[..p].to_vec()),
@@ -70,11 +74,62 @@ fn drop_env_list(ptr: *const *const c_char) {
 
 #[cfg(not(target_os = "linux"))]
 fn drop_env_list(ptr: *const *const c_char) {
-    // FIXME: verify this
-    let mut cur = *ptr;
-    while !ptr.is_null() {
-        unsafe { free(ptr) };
-        ptr = ptr.add(1);
+    // Without libpam_misc, free the entries and the list by hand
+    unsafe {
+        let mut current = ptr;
+        while !(*current).is_null() {
+            crate::alloc::free_c(*current as *mut libc::c_void);
+            current = current.add(1);
+        }
+        crate::alloc::free_c(ptr as *mut libc::c_void);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_env_line, PamEnvList};
+
+    use crate::alloc::{alloc_c, dup_c};
+    use libc::c_char;
+    use std::ffi::CString;
+    use std::{mem, ptr};
+
+    // Allocate an environment list like `pam_getenvlist` does
+    fn env_list(entries: &[&str]) -> *const *const c_char {
+        let list = alloc_c(entries.len() + 1, mem::size_of::<*const c_char>()) as *mut *mut c_char;
+        assert!(!list.is_null());
+        for (i, entry) in entries.iter().enumerate() {
+            unsafe { *list.add(i) = dup_c(&CString::new(*entry).unwrap()) };
+        }
+        list as *const *const c_char
+    }
+
+    #[test]
+    fn env_list_from_ptr() {
+        assert_eq!(PamEnvList::from_ptr(ptr::null()).count(), 0);
+        assert_eq!(PamEnvList::from_ptr(env_list(&[])).count(), 0);
+
+        let list = PamEnvList::from_ptr(env_list(&["KEY=value", "MALFORMED", "EMPTY="]));
+        assert_eq!(
+            list.collect::<Vec<_>>(),
+            [
+                ("KEY".to_string(), "value".to_string()),
+                ("EMPTY".to_string(), String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_env_line_splits_at_first_equals() {
+        let (key, value) = parse_env_line(b"KEY=some=value").unwrap();
+        assert_eq!(key, "KEY");
+        assert_eq!(value, "some=value");
+
+        let (key, value) = parse_env_line(b"=KEY=").unwrap();
+        assert_eq!(key, "=KEY");
+        assert_eq!(value, "");
+
+        assert!(parse_env_line(b"").is_none());
+        assert!(parse_env_line(b"NOVALUE").is_none());
     }
-    unsafe { free(ptr) };
 }
@@ -20,6 +20,8 @@ mod appl {
 
     use libc::c_int;
     use std::ffi::CString;
+    #[cfg(any(test, feature = "test-util"))]
+    use {libc::c_char, std::os::unix::ffi::OsStrExt, std::path::Path};
 
     /// Create the PAM context and initiate the PAM transaction
     ///
@@ -55,21 +57,98 @@ mod appl {
         }
     }
 
+    /// Like `start`, but read the configuration of `service` from `confdir` instead of the
+    /// system configuration (e.g. `/etc/pam.d`)
+    ///
+    /// Meant for testing against a fixed stack, so it is only available with the
+    /// `test-util` feature. This uses `pam_start_confdir` (Linux-PAM 1.4 and newer), which
+    /// is resolved at runtime and fails with `PAM_SYMBOL_ERR` if it is not available, see
+    /// `supports_confdir`.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn start_confdir<'a>(
+        service: &str,
+        user: Option<&str>,
+        conversation: &ffi::pam_conv,
+        confdir: &Path,
+    ) -> PamResult<&'a mut PamHandle> {
+        type StartConfdir = unsafe extern "C" fn(
+            *const c_char,
+            *const c_char,
+            *const ffi::pam_conv,
+            *const c_char,
+            *mut *mut PamHandle,
+        ) -> c_int;
+        const SYMBOL: [u8; 18] = *b"pam_start_confdir\0";
+
+        let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, SYMBOL.as_ptr() as *const c_char) };
+        if symbol.is_null() {
+            return Err(PamReturnCode::Symbol_Err.into());
+        }
+        let start_confdir: StartConfdir = unsafe { std::mem::transmute(symbol) };
+        let (service, user, confdir) = match (
+            CString::new(service),
+            user.map(CString::new).transpose(),
+            CString::new(confdir.as_os_str().as_bytes()),
+        ) {
+            (Ok(service), Ok(user), Ok(confdir)) => (service, user, confdir),
+            _ => return super::buffer_error(),
+        };
+        let mut handle: *mut PamHandle = std::ptr::null_mut();
+        match unsafe {
+            start_confdir(
+                service.as_ptr(),
+                user.as_ref().map_or(std::ptr::null(), |user| user.as_ptr()),
+                conversation,
+                confdir.as_ptr(),
+                &mut handle,
+            )
+        }
+        .into()
+        {
+            PamReturnCode::Success if !handle.is_null() => Ok(unsafe { &mut *handle }),
+            PamReturnCode::Success => Err(PamReturnCode::System_Err.into()),
+            err => Err(err.into()),
+        }
+    }
+
     /// Terminate the PAM transaction
     ///
     /// This function has to be called last in the PAM context.
+    ///
+    /// Pass `data_silent` when ending the transaction in a process which only holds a copy
+    /// of the handle (e.g. after `fork`), so modules release their data without touching
+    /// resources still used by the other process (`PAM_DATA_SILENT`). This is a Linux-PAM
+    /// extension and ignored on other platforms.
+    #[inline]
+    pub fn end(handle: &mut PamHandle, status: PamReturnCode, data_silent: bool) -> PamReturnCode {
+        unsafe { ffi::pam_end(handle, end_status(status, data_silent)) }.into()
+    }
+
+    #[cfg(target_os = "linux")]
     #[inline]
-    pub fn end(handle: &mut PamHandle, status: PamReturnCode) -> PamReturnCode {
-        // FIXME: Add PAM_DATA_SILENT argument?
-        unsafe { ffi::pam_end(handle, status as c_int) }.into()
+    pub(crate) fn end_status(status: PamReturnCode, data_silent: bool) -> c_int {
+        if data_silent {
+            status as c_int | ffi::PAM_DATA_SILENT
+        } else {
+            status as c_int
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[inline]
+    pub(crate) fn end_status(status: PamReturnCode, _data_silent: bool) -> c_int {
+        status as c_int
     }
 
     /// Authenticate the user via the `Conversation` passed to `start`
     ///
     /// Valid `PamFlag`s: Silent, Disallow_Null_AuthTok
+    ///
+    /// Like all functions taking flags, this accepts either a single `PamFlag` or
+    /// multiple flags ORed together as `c_int`.
     #[inline]
-    pub fn authenticate(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_authenticate(handle, flags as c_int) }.into()
+    pub fn authenticate(handle: &mut PamHandle, flags: impl Into<c_int>) -> PamReturnCode {
+        unsafe { ffi::pam_authenticate(handle, flags.into()) }.into()
     }
 
     /// Modify the credentials of the user associated with the PAM transaction
@@ -79,8 +158,8 @@ mod appl {
     ///
     /// Valid `PamFlag`s: Silent, {Establish,Delete,Reinitialize,Refresh}_Cred
     #[inline]
-    pub fn setcred(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_setcred(handle, flags as c_int) }.into()
+    pub fn setcred(handle: &mut PamHandle, flags: impl Into<c_int>) -> PamReturnCode {
+        unsafe { ffi::pam_setcred(handle, flags.into()) }.into()
     }
 
     /// Determine if the user's account is valid
@@ -89,21 +168,21 @@ mod appl {
     ///
     /// Valid `PamFlag`s: Silent, Disallow_Null_AuthTok
     #[inline]
-    pub fn acct_mgmt(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_acct_mgmt(handle, flags as c_int) }.into()
+    pub fn acct_mgmt(handle: &mut PamHandle, flags: impl Into<c_int>) -> PamReturnCode {
+        unsafe { ffi::pam_acct_mgmt(handle, flags.into()) }.into()
     }
 
     /// Set up a user session for a previously authenticated user
     #[inline]
     pub fn open_session(handle: &mut PamHandle, silent: bool) -> PamReturnCode {
-        let flag = silent as c_int;
+        let flag = if silent { PamFlag::Silent as c_int } else { 0 };
         unsafe { ffi::pam_open_session(handle, flag) }.into()
     }
 
     /// Indicate that an authenticated user session has ended
     #[inline]
     pub fn close_session(handle: &mut PamHandle, silent: bool) -> PamReturnCode {
-        let flag = silent as c_int;
+        let flag = if silent { PamFlag::Silent as c_int } else { 0 };
         unsafe { ffi::pam_close_session(handle, flag) }.into()
     }
 
@@ -112,12 +191,31 @@ mod appl {
     ///
     /// Valid `PamFlag`s: Silent, Change_Expired_AuthTok
     #[inline]
-    pub fn chauthtok(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_chauthtok(handle, flags as c_int) }.into()
+    pub fn chauthtok(handle: &mut PamHandle, flags: impl Into<c_int>) -> PamReturnCode {
+        unsafe { ffi::pam_chauthtok(handle, flags.into()) }.into()
     }
 }
 /* ------------------------ <security/pam_appl.h> -------------------------- */
 
+#[cfg(all(test, feature = "client", target_os = "linux"))]
+mod tests {
+    use super::appl::end_status;
+    use crate::{ffi, PamReturnCode};
+
+    #[test]
+    fn end_status_data_silent() {
+        assert_eq!(end_status(PamReturnCode::Success, false), ffi::PAM_SUCCESS);
+        assert_eq!(
+            end_status(PamReturnCode::Success, true),
+            ffi::PAM_SUCCESS | ffi::PAM_DATA_SILENT
+        );
+        assert_eq!(
+            end_status(PamReturnCode::Auth_Err, true),
+            ffi::PAM_AUTH_ERR | ffi::PAM_DATA_SILENT
+        );
+    }
+}
+
 /* ----------------------- <security/_pam_types.h> ------------------------- */
 mod types {
     use crate::{env, ffi, PamHandle, PamItemType, PamResult, PamReturnCode};
@@ -138,22 +236,101 @@ mod types {
         }
     }
 
+    /// Update the string item of type `item_type` in the associated PAM transaction
+    ///
+    /// PAM copies the string, so `item` only needs to be valid for the duration of the call.
+    /// Returns `PAM_BAD_ITEM` if `item_type` is not a string item (e.g. `PamItemType::Conv`).
+    #[inline]
+    pub fn set_item_cstr(
+        handle: &mut PamHandle,
+        item_type: PamItemType,
+        item: &CStr,
+    ) -> PamResult<()> {
+        match item_type {
+            PamItemType::Conv => Err(PamReturnCode::Bad_Item.into()),
+            #[cfg(target_os = "linux")]
+            PamItemType::Fail_Delay | PamItemType::XAuthData => Err(PamReturnCode::Bad_Item.into()),
+            _ => set_item(handle, item_type, unsafe {
+                &*(item.as_ptr() as *const c_void)
+            }),
+        }
+    }
+
+    /// Update the string item of type `item_type` in the associated PAM transaction
+    ///
+    /// Like `set_item_cstr`, but converts `item` first, which fails with `PAM_BUF_ERR`
+    /// if it contains a nul byte.
+    #[inline]
+    pub fn set_item_str(
+        handle: &mut PamHandle,
+        item_type: PamItemType,
+        item: &str,
+    ) -> PamResult<()> {
+        if let Ok(item) = CString::new(item) {
+            set_item_cstr(handle, item_type, &item)
+        } else {
+            super::buffer_error()
+        }
+    }
+
+    /// Reset the item of type `item_type` in the associated PAM transaction to null
+    ///
+    /// Note that applications may not change some items (e.g. `PamItemType::AuthTok` on
+    /// Linux-PAM), in which case PAM returns `PAM_BAD_ITEM`.
+    #[inline]
+    pub fn clear_item(handle: &mut PamHandle, item_type: PamItemType) -> PamResult<()> {
+        match unsafe { ffi::pam_set_item(handle, item_type as c_int, std::ptr::null()) }.into() {
+            PamReturnCode::Success => Ok(()),
+            err => Err(err.into()),
+        }
+    }
+
     /// Retrieve PAM information of type `item_type` from the associated PAM transaction
+    ///
+    /// Returns `None` if the item is not set.
     #[inline]
-    pub fn get_item<'a>(handle: &PamHandle, item_type: PamItemType) -> PamResult<&'a c_void> {
+    pub fn get_item<'a>(
+        handle: &PamHandle,
+        item_type: PamItemType,
+    ) -> PamResult<Option<&'a c_void>> {
         let mut item_ptr: *const c_void = std::ptr::null();
         match unsafe { ffi::pam_get_item(handle, item_type as c_int, &mut item_ptr) }.into() {
-            PamReturnCode::Success => {
-                assert!(
-                    !item_ptr.is_null(),
-                    "Got PAM_SUCCESS from pam_get_item, but ptr is null!"
-                );
-                Ok(unsafe { &*item_ptr })
-            }
+            PamReturnCode::Success => Ok(unsafe { item_ptr.as_ref() }),
             err => Err(err.into()),
         }
     }
 
+    /// Retrieve the string item of type `item_type` from the associated PAM transaction
+    ///
+    /// Returns `None` if the item is not set and `PAM_BAD_ITEM` if `item_type` is not a
+    /// string item (e.g. `PamItemType::Conv`). The string is owned by PAM and changes
+    /// when the item is set again, copy it if you need to keep it.
+    #[inline]
+    pub fn get_item_cstr(handle: &PamHandle, item_type: PamItemType) -> PamResult<Option<&CStr>> {
+        match item_type {
+            PamItemType::Conv => Err(PamReturnCode::Bad_Item.into()),
+            #[cfg(target_os = "linux")]
+            PamItemType::Fail_Delay | PamItemType::XAuthData => Err(PamReturnCode::Bad_Item.into()),
+            _ => get_item(handle, item_type).map(|item| {
+                item.map(|item| unsafe { CStr::from_ptr(item as *const c_void as *const c_char) })
+            }),
+        }
+    }
+
+    /// Retrieve the name of the service (`PamItemType::Service`) of the associated PAM
+    /// transaction
+    ///
+    /// This is the name passed to `pam_start`, unless changed since, and selects the
+    /// service file in use. Returns `None` if it cannot be read, which should not happen
+    /// for a started handle. Invalid UTF-8 is replaced, see `String::from_utf8_lossy`.
+    #[inline]
+    pub fn get_service(handle: &PamHandle) -> Option<String> {
+        match get_item_cstr(handle, PamItemType::Service) {
+            Ok(Some(service)) => Some(service.to_string_lossy().into_owned()),
+            _ => None,
+        }
+    }
+
     /// Retrieve a `CStr` describing the `PamReturnCode` passed, potentially
     /// using LC_MESSAGES to localize the result
     #[inline]
@@ -301,11 +478,84 @@ mod modules {
         }
     }
 
-    // FIXME: implement this properly
-    /*/// Retrieve the object associated with the given `module_data_name` from
-    /// the current PAM context
-    //pub fn get_data(handle: *const PamHandle, module_data_name: *const c_char, data: *const *const c_void);
-     */
+    /// Store `data` with the given `module_data_name` in the current PAM context
+    ///
+    /// `data` is dropped when it is replaced or cleared (see `clear_data`) or when the
+    /// application calls `pam_end`, which runs the cleanup of all data still stored. PAM
+    /// offers no way to enumerate the stored data, so clear each name explicitly to release
+    /// it earlier. Only modules can store data, applications get `PAM_SYSTEM_ERR`.
+    pub fn set_boxed_data<T: 'static>(
+        handle: &mut PamHandle,
+        module_data_name: &str,
+        data: Box<T>,
+    ) -> PamResult<()> {
+        let data = Box::into_raw(data);
+        let result = set_data(
+            handle,
+            module_data_name,
+            unsafe { &mut *(data as *mut c_void) },
+            Some(drop_boxed_data::<T>),
+        );
+        if result.is_err() {
+            // Not stored, so PAM will not run the cleanup
+            drop(unsafe { Box::from_raw(data) });
+        }
+        result
+    }
+
+    // The cleanup of data stored by `set_boxed_data`, run exactly once by PAM
+    pub(crate) unsafe extern "C" fn drop_boxed_data<T>(
+        _handle: *mut PamHandle,
+        data: *mut c_void,
+        _error_status: c_int,
+    ) {
+        if !data.is_null() {
+            drop(Box::from_raw(data as *mut T));
+        }
+    }
+
+    /// Retrieve the data stored with the given `module_data_name` in the current PAM
+    /// context
+    ///
+    /// Returns `None` if there is none, also after `clear_data`.
+    ///
+    /// # Safety
+    ///
+    /// The data has to be of type `T`, e.g. stored by `set_boxed_data::<T>`. It must not be
+    /// replaced or cleared while the returned reference is in use.
+    pub unsafe fn get_data<'a, T>(
+        handle: &'a PamHandle,
+        module_data_name: &str,
+    ) -> PamResult<Option<&'a T>> {
+        let module_data_name = match CString::new(module_data_name) {
+            Ok(name) => name,
+            Err(_) => return super::buffer_error(),
+        };
+        let mut data: *const c_void = std::ptr::null();
+        match ffi::pam_get_data(handle, module_data_name.as_ptr(), &mut data).into() {
+            PamReturnCode::Success => Ok((data as *const T).as_ref()),
+            PamReturnCode::No_Module_Data => Ok(None),
+            err => Err(err.into()),
+        }
+    }
+
+    /// Clear the data stored with the given `module_data_name` in the current PAM context
+    ///
+    /// PAM runs the cleanup registered for the data right away (for `set_boxed_data` this
+    /// drops it), so `pam_end` will not run it again. Clearing a name without data is no
+    /// error.
+    pub fn clear_data(handle: &mut PamHandle, module_data_name: &str) -> PamResult<()> {
+        if let Ok(module_data_name) = CString::new(module_data_name) {
+            let data = std::ptr::null_mut();
+            match unsafe { ffi::pam_set_data(handle, module_data_name.as_ptr(), data, None) }.into()
+            {
+                PamReturnCode::Success => Ok(()),
+                err => Err(err.into()),
+            }
+        } else {
+            super::buffer_error()
+        }
+    }
 
     /// Return the name of the user as specified via `start`
     #[inline]
@@ -331,6 +581,118 @@ mod modules {
 }
 /* ----------------------- <security/pam_modules.h> ------------------------ */
 
+#[cfg(all(test, feature = "module"))]
+mod module_tests {
+    use super::modules::*;
+    use crate::PamReturnCode;
+
+    use std::cell::Cell;
+    use std::ffi::{CStr, CString};
+    use std::rc::Rc;
+
+    struct Counted(Rc<Cell<usize>>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn boxed_data_cleanup() {
+        let drops = Rc::new(Cell::new(0));
+        let data = Box::into_raw(Box::new(Counted(drops.clone())));
+        unsafe {
+            drop_boxed_data::<Counted>(std::ptr::null_mut(), data as _, 0);
+            // Cleared data is null
+            drop_boxed_data::<Counted>(std::ptr::null_mut(), std::ptr::null_mut(), 0);
+        }
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn data_from_application() {
+        let conv = crate::ffi::pam_conv {
+            conv: None,
+            appdata_ptr: std::ptr::null_mut(),
+        };
+        let fixture = crate::client::tests::Fixture::new(crate::client::tests::PERMIT);
+        let handle = crate::start_confdir("pam-f-test", None, &conv, fixture.path()).unwrap();
+        let drops = Rc::new(Cell::new(0));
+
+        // Only modules may store data, the rejected data is not leaked
+        let result = set_boxed_data(handle, "pam-f", Box::new(Counted(drops.clone())));
+        assert_eq!(result.map_err(|e| e.0), Err(PamReturnCode::System_Err));
+        assert_eq!(drops.get(), 1);
+        assert!(clear_data(handle, "pam-f").is_err());
+        assert!(unsafe { get_data::<Vec<u8>>(handle, "pam-f") }.is_err());
+        assert_eq!(
+            clear_data(handle, "pam\0f").map_err(|e| e.0),
+            Err(PamReturnCode::Buf_Err)
+        );
+
+        crate::end(handle, PamReturnCode::Success, false);
+    }
+
+    // Runs its closure from within a module, where PAM allows storing data: in the
+    // conversation, called by `pam_permit` asking for the user
+    #[cfg(feature = "client")]
+    struct InModule<F> {
+        handle: *mut crate::PamHandle,
+        f: Option<F>,
+    }
+
+    #[cfg(feature = "client")]
+    impl<F: FnOnce(&mut crate::PamHandle)> crate::Conversation for InModule<F> {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            if let Some(f) = self.f.take() {
+                f(unsafe { &mut *self.handle });
+            }
+            Ok(CString::new("root").unwrap())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
+            Err(())
+        }
+        fn info(&mut self, _msg: &CStr) {}
+        fn error(&mut self, _msg: &CStr) {}
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn data_round_trip() {
+        let drops = Rc::new(Cell::new(0));
+        let done = Rc::new(Cell::new(false));
+        let (drops_in, done_in) = (drops.clone(), done.clone());
+        let mut conv = InModule {
+            handle: std::ptr::null_mut(),
+            f: Some(move |handle: &mut crate::PamHandle| {
+                set_boxed_data(handle, "pam-f", Box::new(Counted(drops_in.clone()))).unwrap();
+                let data = unsafe { get_data::<Counted>(handle, "pam-f") }.unwrap();
+                assert!(Rc::ptr_eq(&data.unwrap().0, &drops_in));
+
+                clear_data(handle, "pam-f").unwrap();
+                assert_eq!(drops_in.get(), 1);
+                assert!(unsafe { get_data::<Counted>(handle, "pam-f") }
+                    .unwrap()
+                    .is_none());
+                done_in.set(true);
+            }),
+        };
+        let pam_conv = crate::conv::into_pam_conv(&mut conv);
+        let fixture = crate::client::tests::Fixture::new(crate::client::tests::PERMIT);
+        let handle = crate::start_confdir("pam-f-test", None, &pam_conv, fixture.path()).unwrap();
+        conv.handle = handle as *mut crate::PamHandle;
+
+        // A failed assertion fails the conversation
+        assert_eq!(crate::authenticate(handle, 0), PamReturnCode::Success);
+        assert!(done.get());
+        crate::end(handle, PamReturnCode::Success, false);
+        // Not dropped again
+        assert_eq!(drops.get(), 1);
+    }
+}
+
 #[inline]
 fn buffer_error<T>() -> crate::PamResult<T> {
     Err(crate::PamReturnCode::Buf_Err.into())
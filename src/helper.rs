@@ -0,0 +1,378 @@
+//! Running the authentication in a separate helper process
+//!
+//! PAM modules run with the privileges of the process calling them, so hardened services
+//! often keep PAM out of their main process. The parent sends a `HelperRequest` to a small
+//! helper program (calling `serve`), which authenticates with a `Client` and answers with a
+//! `HelperReply`:
+//!
+//! ```no_run
+//! // The helper program
+//! fn main() -> std::io::Result<()> {
+//!     pam_f::helper::serve(std::io::stdin().lock(), std::io::stdout().lock())
+//! }
+//! ```
+//!
+//! ```no_run
+//! // The service, spawning the helper for each authentication
+//! use pam_f::helper::{authenticate_in, HelperRequest};
+//! use std::process::Command;
+//!
+//! let request = HelperRequest {
+//!     service: "login".into(),
+//!     user: "alice".into(),
+//!     password: "secret".into(),
+//! };
+//! let reply = authenticate_in(&mut Command::new("/usr/libexec/auth-helper"), &request)?;
+//! println!("{:?}", reply.code);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! On the pipe, each request and reply is a single frame: its length (4 bytes, big endian)
+//! followed by its fields. Each field is again its length (4 bytes, big endian) followed
+//! by its bytes. A request consists of the service, the user and the password, a reply of
+//! the return code (4 bytes, big endian) followed by the style (likewise) and the text of
+//! each message. The helper does not converse interactively, so modules asking for more
+//! than the user and the password fail.
+
+use crate::{Client, PamMessageStyle, PamResult, PamReturnCode, PasswordConv};
+
+use libc::c_int;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
+use std::process::{Command, Stdio};
+
+// Frames larger than this are rejected, so a broken peer cannot make us allocate a lot
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// What the parent asks the helper to authenticate
+///
+/// With the `zeroize` feature enabled, the password is wiped on drop, like the frames
+/// carrying it.
+#[derive(Clone, PartialEq)]
+pub struct HelperRequest {
+    /// The PAM service name
+    pub service: String,
+    /// The user to authenticate
+    pub user: String,
+    /// The password of `user`
+    pub password: String,
+}
+
+impl fmt::Debug for HelperRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HelperRequest")
+            .field("service", &self.service)
+            .field("user", &self.user)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for HelperRequest {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.password);
+    }
+}
+
+/// The result of the authentication in the helper
+#[derive(Debug, Clone, PartialEq)]
+pub struct HelperReply {
+    /// The result of `Client::authenticate`
+    pub code: PamReturnCode,
+    /// All informational and error messages shown during the authentication, in order
+    pub messages: Vec<(PamMessageStyle, CString)>,
+}
+
+impl HelperReply {
+    /// Whether the authentication succeeded
+    pub fn is_success(&self) -> bool {
+        self.code == PamReturnCode::Success
+    }
+}
+
+/// Authenticate `request` in a helper process started from `command`
+///
+/// The helper gets the request on its standard input and has to write the reply to its
+/// standard output, see `serve`. Its standard error is inherited. Fails with
+/// `UnexpectedEof` if the helper exits (e.g. crashes) without a complete reply and with
+/// `InvalidData` if the reply is malformed.
+pub fn authenticate_in(command: &mut Command, request: &HelperRequest) -> io::Result<HelperReply> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+
+    // A helper exiting early shows up when reading the reply
+    let _ = write_request(&mut stdin, request).and_then(|()| stdin.flush());
+    drop(stdin);
+    let reply = read_reply(&mut stdout);
+    drop(stdout);
+    let status = child.wait()?;
+
+    reply.map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("helper exited without a reply ({})", status),
+        ),
+        _ => err,
+    })
+}
+
+/// Answer a single request read from `input`, writing the reply to `output`
+///
+/// This is the main function of the helper program, see the module documentation. The
+/// request is authenticated with `Client::with_password` and `Client::authenticate`. Fails
+/// only if reading the request or writing the reply fails.
+pub fn serve<R: Read, W: Write>(input: R, output: W) -> io::Result<()> {
+    serve_with(input, output, Client::with_password)
+}
+
+// Like `serve`, starting the transaction for the requested service with `start`
+fn serve_with<'a, R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    start: impl FnOnce(&str) -> PamResult<Client<'a, PasswordConv>>,
+) -> io::Result<()> {
+    let request = read_request(&mut input)?;
+    let reply = match start(&request.service) {
+        Ok(mut client) => {
+            client
+                .conversation_mut()
+                .set_credentials(&request.user, &request.password);
+            let outcome = client.authenticate_retry(NonZeroUsize::new(1).expect("1 is not 0"));
+            HelperReply {
+                code: outcome.code,
+                messages: outcome.messages,
+            }
+        }
+        Err(err) => HelperReply {
+            code: err.0,
+            messages: Vec::new(),
+        },
+    };
+    write_reply(&mut output, &reply)?;
+    output.flush()
+}
+
+/// Write `request` to `writer` in the format of the helper protocol
+pub fn write_request<W: Write>(writer: &mut W, request: &HelperRequest) -> io::Result<()> {
+    let mut body = Vec::new();
+    put_field(&mut body, request.service.as_bytes());
+    put_field(&mut body, request.user.as_bytes());
+    put_field(&mut body, request.password.as_bytes());
+    let result = write_frame(writer, &body);
+    wipe(&mut body);
+    result
+}
+
+/// Read a request in the format of the helper protocol from `reader`
+pub fn read_request<R: Read>(reader: &mut R) -> io::Result<HelperRequest> {
+    let mut body = read_frame(reader)?;
+    let result = (|| {
+        let mut fields = Fields(&body);
+        let request = HelperRequest {
+            service: fields.next_string()?,
+            user: fields.next_string()?,
+            password: fields.next_string()?,
+        };
+        fields.finish()?;
+        Ok(request)
+    })();
+    wipe(&mut body);
+    result
+}
+
+/// Write `reply` to `writer` in the format of the helper protocol
+pub fn write_reply<W: Write>(writer: &mut W, reply: &HelperReply) -> io::Result<()> {
+    let mut body = Vec::new();
+    put_field(&mut body, &(reply.code as c_int).to_be_bytes());
+    for (style, text) in &reply.messages {
+        put_field(&mut body, &(*style as c_int).to_be_bytes());
+        put_field(&mut body, text.as_bytes());
+    }
+    write_frame(writer, &body)
+}
+
+/// Read a reply in the format of the helper protocol from `reader`
+pub fn read_reply<R: Read>(reader: &mut R) -> io::Result<HelperReply> {
+    let body = read_frame(reader)?;
+    let mut fields = Fields(&body);
+    let code = PamReturnCode::from(fields.next_int()?);
+    let mut messages = Vec::new();
+    while !fields.0.is_empty() {
+        let style = PamMessageStyle::from(fields.next_int()?);
+        let text = CString::new(fields.next()?).map_err(|_| invalid("nul byte in message"))?;
+        messages.push((style, text));
+    }
+    Ok(HelperReply { code, messages })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn wipe(buf: &mut Vec<u8>) {
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(buf);
+    #[cfg(not(feature = "zeroize"))]
+    buf.clear();
+}
+
+fn len_prefix(len: usize) -> io::Result<[u8; 4]> {
+    if len > MAX_FRAME_LEN {
+        return Err(invalid("frame too long"));
+    }
+    Ok((len as u32).to_be_bytes())
+}
+
+fn put_field(body: &mut Vec<u8>, field: &[u8]) {
+    // Fields longer than a frame are caught when writing the frame
+    body.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    body.extend_from_slice(field);
+}
+
+fn write_frame<W: Write>(writer: &mut W, body: &[u8]) -> io::Result<()> {
+    writer.write_all(&len_prefix(body.len())?)?;
+    writer.write_all(body)
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid("frame too long"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// The fields of a frame which have not been read yet
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> io::Result<&'a [u8]> {
+        if self.0.len() < 4 {
+            return Err(invalid("truncated field"));
+        }
+        let (len, rest) = self.0.split_at(4);
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if rest.len() < len {
+            return Err(invalid("truncated field"));
+        }
+        let (field, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn next_string(&mut self) -> io::Result<String> {
+        let field = self.next()?;
+        if field.contains(&0) {
+            return Err(invalid("nul byte in field"));
+        }
+        String::from_utf8(field.to_vec()).map_err(|err| {
+            wipe(&mut err.into_bytes());
+            invalid("field is not UTF-8")
+        })
+    }
+
+    fn next_int(&mut self) -> io::Result<c_int> {
+        match *self.next()? {
+            [a, b, c, d] => Ok(c_int::from_be_bytes([a, b, c, d])),
+            _ => Err(invalid("malformed integer field")),
+        }
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("trailing data"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::client::tests::password_fixture;
+    use std::io::Cursor;
+
+    fn request() -> HelperRequest {
+        HelperRequest {
+            service: "pam-f-test".into(),
+            user: "root".into(),
+            password: "wrong".into(),
+        }
+    }
+
+    #[test]
+    fn protocol_round_trip() {
+        let mut buf = Vec::new();
+        write_request(&mut buf, &request()).unwrap();
+        assert_eq!(&buf[..8], [0, 0, 0, 31, 0, 0, 0, 10]);
+        assert_eq!(read_request(&mut Cursor::new(&buf)).unwrap(), request());
+        assert!(!format!("{:?}", request()).contains("wrong"));
+
+        let reply = HelperReply {
+            code: PamReturnCode::Auth_Err,
+            messages: vec![(
+                PamMessageStyle::Error_Msg,
+                CString::new("Account locked").unwrap(),
+            )],
+        };
+        let mut buf = Vec::new();
+        write_reply(&mut buf, &reply).unwrap();
+        assert_eq!(read_reply(&mut Cursor::new(&buf)).unwrap(), reply);
+
+        // Truncated and oversized frames
+        let err = read_reply(&mut Cursor::new(&buf[..buf.len() - 1])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_reply(&mut Cursor::new([0xff, 0xff, 0xff, 0xff])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_request(&mut Cursor::new([0, 0, 0, 4, 0, 0, 0, 9])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn helper_process() {
+        // Run the helper side in process, then replay its reply from a child
+        let mut input = Vec::new();
+        write_request(&mut input, &request()).unwrap();
+        let fixture = password_fixture();
+        let mut output = Vec::new();
+        serve_with(Cursor::new(input), &mut output, |service| {
+            Client::with_confdir(service, fixture.path(), PasswordConv::new())
+        })
+        .unwrap();
+        let expected = read_reply(&mut Cursor::new(&output)).unwrap();
+        assert_eq!(expected.code, PamReturnCode::Auth_Err);
+
+        let path = std::env::temp_dir().join(format!("pam-f-helper-{}", std::process::id()));
+        std::fs::write(&path, &output).unwrap();
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("cat > /dev/null; cat \"$0\"")
+            .arg(&path);
+        let reply = authenticate_in(&mut command, &request());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reply.unwrap(), expected);
+
+        // A helper crashing halfway through its reply
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("cat > /dev/null; printf '\\000\\000\\000\\010'; kill -9 $$");
+        let err = authenticate_in(&mut command, &request()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("signal"), "{}", err);
+    }
+}
@@ -12,23 +12,43 @@
 // Reexport pam_sys so downstream users don't need to depend on it
 pub use pam_sys as ffi;
 
+pub mod alloc;
+#[cfg(feature = "syslog")]
+mod audit;
 mod conv;
 mod enums;
 mod env;
 mod functions;
+mod notice;
+mod probe;
 mod types;
 
-pub use crate::{enums::*, functions::*, types::*};
+pub use crate::notice::{parse_expiry_notice, ExpiryInfo};
+pub use crate::{enums::*, functions::*, probe::*, types::*};
 
 #[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "helper")]
+pub mod helper;
 #[cfg(feature = "module")]
 pub mod module;
 
-pub use crate::conv::{Conversation, PasswordConv};
-
+#[cfg(target_os = "linux")]
+pub use crate::conv::BinaryConv;
+#[cfg(target_os = "linux")]
+pub use crate::conv::MiscConv;
+pub use crate::conv::{
+    BinaryPacket, ChannelConv, ChannelMessage, Conversation, ConversationExt, KeyringConv,
+    LimitedConv, MessageContent, NullConv, PasswordConv, PolicyConv, RawMessage, Response,
+    SecretStore, StyleFilterConv, TimingConv,
+};
+#[cfg(feature = "test-util")]
+pub use crate::conv::{EnvConv, MessageBatch, MessageBatchBuilder};
+
+#[cfg(feature = "syslog")]
+pub use audit::AuditLog;
 #[cfg(feature = "client")]
-pub use client::Client;
+pub use client::{AuthOutcome, Client, ItemSet, LoginError, LoginOk, PasswordChange};
 
 #[cfg(feature = "module")]
 pub use module::PamModule;
@@ -2,9 +2,12 @@
 //!
 //! Inspired by anowell/pam-rs
 
-use crate::{PamHandle, PamReturnCode};
-use std::ffi::CStr;
-use std::os::raw::c_uint;
+use crate::alloc::free_c;
+use crate::{ffi, get_item, PamHandle, PamItemType, PamMessage, PamMessageStyle, PamResponse};
+use crate::{PamResult, PamReturnCode};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
 
 // FIXME: Find a solution for the flags containing ORed integers
 #[allow(unused_variables)]
@@ -14,7 +17,7 @@ use std::os::raw::c_uint;
 /// This exports the respective functions at the expected symbols prefixed with `pam_sm_`.
 ///
 /// ```no_run
-/// use pam::{PamModule, export_pam_module};
+/// use pam_f::{PamModule, export_pam_module};
 ///
 /// pub struct MyModule;
 /// impl PamModule for MyModule {}
@@ -43,6 +46,203 @@ pub trait PamModule {
     }
 }
 
+/// Convert the module arguments passed by PAM into a `Vec` of `&CStr`
+///
+/// These are the options following the module name in the service file, e.g.
+/// `use_first_pass` and `debug`. A negative `argc` or a null `argv` yields no arguments
+/// and null entries are skipped.
+///
+/// # Safety
+///
+/// Unless null, `argv` has to point to at least `argc` pointers, each of them null or
+/// pointing to a nul-terminated string which outlives `'a`.
+pub unsafe fn convert_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
+    if argc <= 0 || argv.is_null() {
+        return Vec::new();
+    }
+    (0..argc as usize)
+        .map(|i| *argv.add(i))
+        .filter(|arg| !arg.is_null())
+        .map(|arg| CStr::from_ptr(arg))
+        .collect()
+}
+
+/// Module arguments split into bare flags and `key=value` options
+///
+/// ```
+/// use pam_f::module::ModuleArgs;
+/// use std::ffi::CString;
+///
+/// let raw = ["debug", "retry=3"].map(|a| CString::new(a).unwrap());
+/// let args: Vec<_> = raw.iter().map(|a| a.as_c_str()).collect();
+///
+/// let args = ModuleArgs::parse(&args);
+/// assert!(args.has_flag("debug"));
+/// assert_eq!(args.value("retry"), Some("3"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ModuleArgs<'a> {
+    args: Vec<(&'a [u8], Option<&'a [u8]>)>,
+}
+
+impl<'a> ModuleArgs<'a> {
+    /// Split each argument at the first `=`, arguments without one are flags
+    pub fn parse(args: &[&'a CStr]) -> ModuleArgs<'a> {
+        let args = args
+            .iter()
+            .map(|arg| {
+                let arg = arg.to_bytes();
+                match memchr::memchr(b'=', arg) {
+                    Some(pos) => (&arg[..pos], Some(&arg[pos + 1..])),
+                    None => (arg, None),
+                }
+            })
+            .collect();
+        ModuleArgs { args }
+    }
+
+    /// Whether the bare flag `name` was given
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.args
+            .iter()
+            .any(|(key, value)| value.is_none() && *key == name.as_bytes())
+    }
+
+    /// The value of the option `key`
+    ///
+    /// If it was given multiple times, the last one wins. Returns `None` if it was not
+    /// given or its value is not valid UTF-8.
+    pub fn value(&self, key: &str) -> Option<&'a str> {
+        self.args
+            .iter()
+            .rev()
+            .find(|(k, value)| value.is_some() && *k == key.as_bytes())
+            .and_then(|(_, value)| std::str::from_utf8((*value)?).ok())
+    }
+
+    /// All arguments in order, as name and an optional value
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)> + '_ {
+        self.args.iter().copied()
+    }
+}
+
+/// Send `messages` to the application, using the conversation function of `handle`
+///
+/// Returns exactly one entry per message, in order:
+/// - `Some` with the answer to `Prompt_Echo_Off`, `Prompt_Echo_On` and `Radio_Type`
+///   prompts, or `None` if the application gave no answer.
+/// - Always `None` for `Text_Info` and `Error_Msg`, which have no answer. Anything the
+///   application returned for them anyway is ignored.
+///
+/// The response array and all strings in it are freed before returning, also if the
+/// conversation fails. With the `zeroize` feature enabled, the strings are wiped first.
+/// An empty batch does not invoke the conversation at all.
+pub fn converse(
+    handle: &PamHandle,
+    messages: &[(PamMessageStyle, &CStr)],
+) -> PamResult<Vec<Option<CString>>> {
+    let conv = match get_item(handle, PamItemType::Conv)? {
+        Some(conv) => unsafe { &*(conv as *const c_void as *const ffi::pam_conv) },
+        None => return Err(PamReturnCode::Conv_Err.into()),
+    };
+    unsafe { converse_with(conv, messages) }
+}
+
+// Owns the response array returned by a conversation function, so it is freed on all paths
+struct Responses {
+    resp: *mut PamResponse,
+    len: usize,
+}
+
+impl Responses {
+    // Take the answer to message `i` out of the array
+    unsafe fn take(&mut self, i: usize) -> Option<CString> {
+        let r = &mut *self.resp.add(i);
+        if r.resp.is_null() {
+            return None;
+        }
+        let answer = CStr::from_ptr(r.resp).to_owned();
+        free_response(r);
+        Some(answer)
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        if self.resp.is_null() {
+            return;
+        }
+        unsafe {
+            for i in 0..self.len {
+                free_response(&mut *self.resp.add(i));
+            }
+            free_c(self.resp as *mut c_void);
+        }
+    }
+}
+
+unsafe fn free_response(r: &mut PamResponse) {
+    if r.resp.is_null() {
+        return;
+    }
+    #[cfg(feature = "zeroize")]
+    {
+        use zeroize::Zeroize;
+        let len = CStr::from_ptr(r.resp).to_bytes().len();
+        std::slice::from_raw_parts_mut(r.resp as *mut u8, len).zeroize();
+    }
+    free_c(r.resp as *mut c_void);
+    r.resp = ptr::null_mut();
+}
+
+pub(crate) unsafe fn converse_with(
+    conv: &ffi::pam_conv,
+    messages: &[(PamMessageStyle, &CStr)],
+) -> PamResult<Vec<Option<CString>>> {
+    if messages.is_empty() {
+        return Ok(Vec::new());
+    }
+    let num_msg = c_int::try_from(messages.len()).map_err(|_| PamReturnCode::Buf_Err)?;
+    let function = conv.conv.ok_or(PamReturnCode::Conv_Err)?;
+
+    let pam_messages: Vec<PamMessage> = messages
+        .iter()
+        .map(|(style, text)| PamMessage {
+            msg_style: *style as c_int,
+            msg: text.as_ptr() as _,
+        })
+        .collect();
+    let mut pointers: Vec<*const PamMessage> = pam_messages.iter().map(|m| m as _).collect();
+
+    let mut resp: *mut PamResponse = ptr::null_mut();
+    let code = function(
+        num_msg,
+        pointers.as_mut_ptr() as _,
+        &mut resp,
+        conv.appdata_ptr,
+    );
+    let mut responses = Responses {
+        resp,
+        len: messages.len(),
+    };
+    match PamReturnCode::from(code) {
+        PamReturnCode::Success => {}
+        code => return Err(code.into()),
+    }
+    if responses.resp.is_null() {
+        return Ok(vec![None; messages.len()]);
+    }
+
+    Ok(messages
+        .iter()
+        .enumerate()
+        .map(|(i, (style, _))| match style {
+            PamMessageStyle::Text_Info | PamMessageStyle::Error_Msg => None,
+            _ => responses.take(i),
+        })
+        .collect())
+}
+
 #[macro_export]
 /// Export the given struct as a PAM module by wiring up the respective extern "C" functions
 macro_rules! export_pam_module {
@@ -54,9 +254,8 @@ macro_rules! export_pam_module {
             use $crate::{PamHandle, PamModule, PamReturnCode};
 
             fn convert_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
-                (0..argc)
-                    .map(|i| unsafe { CStr::from_ptr(*argv.offset(i as isize)) })
-                    .collect()
+                // PAM passes the arguments of the module from the service file
+                unsafe { $crate::module::convert_args(argc, argv) }
             }
 
             #[no_mangle]
@@ -125,11 +324,111 @@ macro_rules! export_pam_module {
 
 #[cfg(test)]
 pub mod test {
-    use super::PamModule;
-    use crate::export_pam_module;
+    use super::*;
+
+    use crate::alloc::{alloc_c, dup_c, tests::live_allocations};
+    use std::cell::Cell;
+    use std::mem;
+
+    // Answers every message (also info and error messages) with its own text, allocating
+    // like PAM would
+    unsafe extern "C" fn echo_conv(
+        num_msg: c_int,
+        msg: *mut *const PamMessage,
+        out_resp: *mut *mut PamResponse,
+        appdata_ptr: *mut c_void,
+    ) -> c_int {
+        let resp = alloc_c(num_msg as usize, mem::size_of::<PamResponse>()) as *mut PamResponse;
+        for i in 0..num_msg as usize {
+            let m = &**msg.add(i);
+            // Leave the answer to empty messages null
+            if *m.msg != 0 {
+                (*resp.add(i)).resp = dup_c(CStr::from_ptr(m.msg));
+            }
+        }
+        *out_resp = resp;
+        (*(appdata_ptr as *const Cell<c_int>)).get()
+    }
 
     pub struct TestModule;
     impl PamModule for TestModule {}
 
     export_pam_module!(TestModule);
+
+    #[test]
+    fn convert_crafted_argv() {
+        let debug = CString::new("debug").unwrap();
+        let retry = CString::new("retry=3").unwrap();
+        let argv = [debug.as_ptr(), ptr::null(), retry.as_ptr()];
+
+        let args = unsafe { convert_args(argv.len() as c_int, argv.as_ptr()) };
+        assert_eq!(args, vec![debug.as_c_str(), retry.as_c_str()]);
+        // Only the first `argc` entries are read
+        let args = unsafe { convert_args(1, argv.as_ptr()) };
+        assert_eq!(args, vec![debug.as_c_str()]);
+
+        assert!(unsafe { convert_args(0, argv.as_ptr()) }.is_empty());
+        assert!(unsafe { convert_args(-1, argv.as_ptr()) }.is_empty());
+        assert!(unsafe { convert_args(3, ptr::null()) }.is_empty());
+    }
+
+    #[test]
+    fn parse_module_args() {
+        let raw: Vec<_> = [
+            "use_first_pass",
+            "retry=3",
+            "prompt=Code: a=b",
+            "empty=",
+            "retry=5",
+        ]
+        .iter()
+        .map(|a| CString::new(*a).unwrap())
+        .collect();
+        let invalid = CString::new(b"bad=\xff".to_vec()).unwrap();
+        let mut args: Vec<_> = raw.iter().map(|a| a.as_c_str()).collect();
+        args.push(&invalid);
+
+        let args = ModuleArgs::parse(&args);
+        assert!(args.has_flag("use_first_pass"));
+        assert!(!args.has_flag("debug"));
+        assert!(!args.has_flag("retry"));
+        assert_eq!(args.value("retry"), Some("5"));
+        assert_eq!(args.value("prompt"), Some("Code: a=b"));
+        assert_eq!(args.value("empty"), Some(""));
+        assert_eq!(args.value("use_first_pass"), None);
+        assert_eq!(args.value("bad"), None);
+        assert_eq!(args.iter().count(), 6);
+    }
+
+    #[test]
+    fn converse_responses() {
+        let code = Cell::new(PamReturnCode::Success as c_int);
+        let conv = ffi::pam_conv {
+            conv: Some(echo_conv),
+            appdata_ptr: &code as *const Cell<c_int> as *mut c_void,
+        };
+        let login = CString::new("login: ").unwrap();
+        let info = CString::new("Welcome").unwrap();
+        let empty = CString::default();
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, login.as_c_str()),
+            (PamMessageStyle::Text_Info, info.as_c_str()),
+            (PamMessageStyle::Prompt_Echo_Off, empty.as_c_str()),
+            (PamMessageStyle::Error_Msg, info.as_c_str()),
+        ];
+
+        // All strings and the array are freed, also on failure
+        let before = live_allocations();
+        let answers = unsafe { converse_with(&conv, &messages) }.unwrap();
+        assert_eq!(answers, vec![Some(login.clone()), None, None, None]);
+        assert_eq!(live_allocations(), before);
+
+        code.set(PamReturnCode::Conv_Err as c_int);
+        let result = unsafe { converse_with(&conv, &messages) };
+        assert_eq!(result.map_err(|e| e.0), Err(PamReturnCode::Conv_Err));
+        assert_eq!(live_allocations(), before);
+
+        assert_eq!(unsafe { converse_with(&conv, &[]) }.unwrap(), vec![]);
+        assert_eq!(live_allocations(), before);
+    }
 }
@@ -0,0 +1,113 @@
+//! Recognizing common notices of modules
+//!
+//! Modules pass information such as an upcoming password expiry as free-form text
+//! messages. The functions in this module turn the messages of well-known modules into
+//! structured data, e.g. to present them in a graphical login screen.
+
+use std::ffi::CStr;
+
+/// The password expiry announced by a module, see `parse_expiry_notice`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExpiryInfo {
+    /// The password expires in the given number of days
+    ExpiresIn {
+        /// The number of days left, 0 if it expires today
+        days: u32,
+    },
+    /// The password has to be changed right away
+    ChangeRequired,
+}
+
+// The leading part of the notices announcing the days left, in lowercase
+const EXPIRES_IN: &str = "your password will expire in ";
+
+// Notices requiring a change right away, in lowercase (pam_unix and sssd)
+const CHANGE_REQUIRED: [&str; 2] = [
+    "you are required to change your password immediately",
+    "password expired. change your password now.",
+];
+
+/// Recognize a message about the expiry of the password
+///
+/// Understands the messages of `pam_unix` (e.g. `Warning: your password will expire in
+/// 3 days.` or `You are required to change your password immediately (password expired)`)
+/// and similar ones of `pam_sss` (e.g. `Your password will expire in 1 day(s).`), ignoring
+/// case and surrounding whitespace. Returns `None` for any other message, including
+/// translated ones.
+pub fn parse_expiry_notice(msg: &CStr) -> Option<ExpiryInfo> {
+    let msg = msg.to_str().ok()?.trim().to_ascii_lowercase();
+
+    if CHANGE_REQUIRED.iter().any(|notice| msg.starts_with(notice)) {
+        return Some(ExpiryInfo::ChangeRequired);
+    }
+
+    let msg = msg.strip_prefix("warning: ").unwrap_or(&msg);
+    let rest = msg.strip_prefix(EXPIRES_IN)?;
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    if digits == 0 {
+        return None;
+    }
+    let days = rest[..digits].parse().ok()?;
+    let unit = &rest[digits..];
+    match unit.strip_suffix('.').unwrap_or(unit) {
+        " day" | " days" | " day(s)" => Some(ExpiryInfo::ExpiresIn { days }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+
+    fn parse(msg: &str) -> Option<ExpiryInfo> {
+        parse_expiry_notice(&CString::new(msg).unwrap())
+    }
+
+    #[test]
+    fn expiry_notices() {
+        let expires_in = |days| Some(ExpiryInfo::ExpiresIn { days });
+        assert_eq!(
+            parse("Warning: your password will expire in 1 day."),
+            expires_in(1)
+        );
+        assert_eq!(
+            parse("Warning: your password will expire in 14 days."),
+            expires_in(14)
+        );
+        assert_eq!(
+            parse("Warning: your password will expire in 0 day(s)."),
+            expires_in(0)
+        );
+        assert_eq!(
+            parse("Your password will expire in 3 day(s).\n"),
+            expires_in(3)
+        );
+        assert_eq!(
+            parse("You are required to change your password immediately (password expired)"),
+            Some(ExpiryInfo::ChangeRequired)
+        );
+        assert_eq!(
+            parse("You are required to change your password immediately (administrator enforced)"),
+            Some(ExpiryInfo::ChangeRequired)
+        );
+        assert_eq!(
+            parse("Password expired. Change your password now."),
+            Some(ExpiryInfo::ChangeRequired)
+        );
+
+        // Never guess
+        assert_eq!(parse("Warning: your password will expire soon."), None);
+        assert_eq!(parse("Your password will expire in 3 weeks."), None);
+        assert_eq!(parse("Your password will expire in 3 days or so"), None);
+        assert_eq!(parse("Your password will expire in -3 days."), None);
+        assert_eq!(
+            parse("Your password will expire in 99999999999 days."),
+            None
+        );
+        assert_eq!(parse("Your account will expire in 3 days."), None);
+        assert_eq!(parse("Last login: Mon Oct 12 09:00:00"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -0,0 +1,87 @@
+//! Runtime detection of optional PAM features
+//!
+//! Not every PAM implementation (or version) provides all functions and items. The
+//! functions in this module allow portable code to check for a feature and choose a
+//! fallback instead of failing hard.
+
+use crate::{ffi, PamHandle, PamItemType, PamReturnCode};
+
+use libc::{c_char, c_int, c_void};
+use std::ffi::CStr;
+
+/// Check whether the function `symbol` can be resolved at runtime
+///
+/// `symbol` has to be a nul-terminated byte string, e.g. `b"pam_get_authtok\0"`.
+pub fn supports_symbol(symbol: &[u8]) -> bool {
+    match CStr::from_bytes_with_nul(symbol) {
+        Ok(symbol) => unsafe {
+            !libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr() as *const c_char).is_null()
+        },
+        Err(_) => false,
+    }
+}
+
+/// Check whether `pam_start_confdir` is available (Linux-PAM 1.4 and newer)
+pub fn supports_confdir() -> bool {
+    supports_symbol(b"pam_start_confdir\0")
+}
+
+/// Check whether `pam_get_authtok` is available (Linux-PAM extension)
+pub fn supports_get_authtok() -> bool {
+    supports_symbol(b"pam_get_authtok\0")
+}
+
+/// The PAM implementations known to this crate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PamImplementation {
+    /// Linux-PAM, as used by most Linux distributions
+    LinuxPam,
+    /// OpenPAM, as used by macOS and the BSDs
+    OpenPam,
+    /// Neither of the above could be detected
+    Unknown,
+}
+
+/// Detect the PAM implementation this process is linked against
+///
+/// This checks for functions only one of the implementations provides, i.e.
+/// `pam_syslog` for Linux-PAM and `openpam_ttyconv` for OpenPAM.
+pub fn pam_implementation() -> PamImplementation {
+    if supports_symbol(b"pam_syslog\0") {
+        PamImplementation::LinuxPam
+    } else if supports_symbol(b"openpam_ttyconv\0") {
+        PamImplementation::OpenPam
+    } else {
+        PamImplementation::Unknown
+    }
+}
+
+/// Check whether the PAM implementation behind `handle` knows the item `item_type`
+///
+/// Unknown items are reported as `PAM_BAD_ITEM` by `pam_get_item`. Note that items which
+/// are only accessible to modules (like `PamItemType::AuthTok` on Linux-PAM) are reported
+/// as unsupported when called from an application.
+pub fn supports_item(handle: &PamHandle, item_type: PamItemType) -> bool {
+    let mut item_ptr: *const c_void = std::ptr::null();
+    let code: PamReturnCode =
+        unsafe { ffi::pam_get_item(handle, item_type as c_int, &mut item_ptr) }.into();
+    code != PamReturnCode::Bad_Item
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols() {
+        assert!(supports_symbol(b"pam_start\0"));
+        assert!(!supports_symbol(b"pam_f_does_not_exist\0"));
+        assert!(!supports_symbol(b"pam_start"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn implementation() {
+        assert_eq!(pam_implementation(), PamImplementation::LinuxPam);
+    }
+}
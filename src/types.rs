@@ -1,4 +1,4 @@
-use crate::enums::PamReturnCode;
+use crate::enums::{PamItemType, PamReturnCode};
 
 /// Opaque PAM main structure. Used for nearly all application functions
 pub type PamHandle = pam_sys::pam_handle_t;
@@ -36,3 +36,90 @@ impl From<PamReturnCode> for PamError {
         PamError(err)
     }
 }
+
+/// Wraps the `PamError`, which can be recovered with `std::io::Error::get_ref`
+///
+/// `PAM_AUTH_ERR` and `PAM_PERM_DENIED` map to `PermissionDenied`, `PAM_BUF_ERR` to
+/// `OutOfMemory` and everything else to `Other`.
+impl From<PamError> for std::io::Error {
+    fn from(err: PamError) -> std::io::Error {
+        use std::io::ErrorKind;
+
+        let kind = match err.0 {
+            PamReturnCode::Auth_Err | PamReturnCode::Perm_Denied => ErrorKind::PermissionDenied,
+            PamReturnCode::Buf_Err => ErrorKind::OutOfMemory,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+/// See `From<PamError>`
+impl From<PamReturnCode> for std::io::Error {
+    fn from(err: PamReturnCode) -> std::io::Error {
+        PamError(err).into()
+    }
+}
+
+/// Error setting the item `item`
+pub struct ItemError {
+    /// The item which could not be set
+    pub item: PamItemType,
+    /// The error returned for it
+    pub error: PamError,
+}
+
+impl std::fmt::Debug for ItemError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}: {:?}", self.item, self.error)
+    }
+}
+
+impl std::fmt::Display for ItemError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}: {}", self.item, self.error)
+    }
+}
+
+impl std::error::Error for ItemError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<ItemError> for PamError {
+    fn from(err: ItemError) -> PamError {
+        err.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    #[test]
+    fn into_io_error() {
+        let err = io::Error::from(PamError(PamReturnCode::Auth_Err));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().starts_with("Auth_Err"));
+        let inner = err.get_ref().unwrap().downcast_ref::<PamError>().unwrap();
+        assert_eq!(inner.0, PamReturnCode::Auth_Err);
+
+        let kind = |code: PamReturnCode| io::Error::from(code).kind();
+        assert_eq!(
+            kind(PamReturnCode::Perm_Denied),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(kind(PamReturnCode::Buf_Err), io::ErrorKind::OutOfMemory);
+        assert_eq!(kind(PamReturnCode::Service_Err), io::ErrorKind::Other);
+
+        fn login() -> io::Result<()> {
+            let result: PamResult<()> = Err(PamReturnCode::Conv_Err.into());
+            result?;
+            Ok(())
+        }
+        assert_eq!(login().unwrap_err().kind(), io::ErrorKind::Other);
+    }
+}